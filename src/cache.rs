@@ -0,0 +1,295 @@
+//! Cache-aside reads of Postgres rows through Redis: look in Redis, fall back to Postgres on a miss,
+//! then store what was found for next time, i.e.
+//! ```ignore
+//! let users = CacheAside::new(&rpool, &pool, "user", "SELECT id, name FROM users WHERE id = $1", User::from_row)
+//!     .ttl(Duration::from_secs(600));
+//! let user: Option<User> = users.get(&42i64).await?;
+//! // after writing to the row
+//! users.invalidate(&42i64).await?;
+//! ```
+//! Rows that don't exist are cached too, for a shorter time, so repeated lookups of a missing key don't all hit Postgres.
+//! Concurrent misses on the same key share a single Postgres query instead of stampeding it.
+//! Rows are cached as JSON unless another codec is given, i.e. .codec(Zstd::new(MessagePack)) for big hot rows.
+//! If Redis can't be reached the error is logged and reads go straight to Postgres
+
+use std::{collections::HashMap, fmt::Display, sync::{Arc, Mutex}, time::Duration};
+use futures::StreamExt;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::OnceCell;
+use tokio_postgres::types::ToSql;
+use crate::core::GenericError;
+use crate::postgres::{self, ConnPool, Row, SimpleConfig};
+use crate::redis::{RedisPool, codec::{Codec, Json}, rediserde};
+
+const DEFAULT_TTL_SECONDS: u64 = 300;
+const DEFAULT_NEGATIVE_TTL_SECONDS: u64 = 30;
+
+
+/// Called with the Redis key of every entry that is invalidated
+pub type InvalidateHook = Box<dyn Fn(&str) + Send + Sync>;
+
+/// Reads rows of type T by a key of type K, caching them in Redis under "{prefix}:{key}" encoded with C
+pub struct CacheAside<K, T, F, C = Json> {
+    redis: RedisPool,
+    pg: ConnPool,
+    prefix: String,
+    /// takes the key as $1 and returns at most one row
+    query: String,
+    rowfunc: F,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hooks: Vec<InvalidateHook>,
+    codec: C,
+    /// loads in progress by Redis key, shared by concurrent misses. An entry is the key's current version:
+    /// invalidating removes it, so a load that was already running doesn't cache what it read
+    inflight: Mutex<HashMap<String, Arc<OnceCell<Option<T>>>>>,
+    _key: std::marker::PhantomData<fn(&K)>,
+}
+
+impl<K, T, F> CacheAside<K, T, F>
+where
+    K: ToSql + Sync + Display,
+    T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    F: Fn(&Row) -> T + Send + Sync,
+{
+
+    /// Instantiate a cache for the rows returned by query, which takes the key as its only parameter
+    pub fn new(redis: &RedisPool, pg: &ConnPool, prefix: &str, query: &str, rowfunc: F) -> Self {
+        CacheAside {
+            redis: redis.clone(),
+            pg: pg.clone(),
+            prefix: prefix.to_string(),
+            query: query.to_string(),
+            rowfunc,
+            ttl: Duration::from_secs(DEFAULT_TTL_SECONDS),
+            negative_ttl: Duration::from_secs(DEFAULT_NEGATIVE_TTL_SECONDS),
+            hooks: Vec::new(),
+            codec: Json,
+            inflight: Mutex::new(HashMap::new()),
+            _key: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<K, T, F, C> CacheAside<K, T, F, C>
+where
+    K: ToSql + Sync + Display,
+    T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    F: Fn(&Row) -> T + Send + Sync,
+    C: Codec,
+{
+
+    /// Cache rows encoded with codec instead of JSON. Rows already cached in another format are still read
+    pub fn codec<D: Codec>(self, codec: D) -> CacheAside<K, T, F, D> {
+        CacheAside {
+            redis: self.redis,
+            pg: self.pg,
+            prefix: self.prefix,
+            query: self.query,
+            rowfunc: self.rowfunc,
+            ttl: self.ttl,
+            negative_ttl: self.negative_ttl,
+            hooks: self.hooks,
+            codec,
+            inflight: Mutex::new(HashMap::new()),
+            _key: std::marker::PhantomData,
+        }
+    }
+
+    /// How long a row stays cached
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long a missing row is remembered as missing. Zero turns off caching of misses
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Run hook after an entry is invalidated, i.e. to tell other services or clear a local cache
+    pub fn on_invalidate(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// The Redis key a row is cached under
+    pub fn redis_key(&self, key: &K) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+
+    /// Get the row for key from Redis, or from Postgres if it isn't cached
+    pub async fn get(&self, key: &K) -> Result<Option<T>, GenericError> {
+        let rkey = self.redis_key(key);
+        // a cached Option<T> of None records a missing row
+        match rediserde::get::<Option<T>>(&self.redis, &rkey).await {
+            Ok(Some(cached)) => return Ok(cached),
+            Ok(None) => (),
+            Err(e) => println!("ERROR! CacheAside could not read {} from Redis: {}", rkey, e),
+        }
+        let cell = self.inflight.lock().unwrap().entry(rkey.clone()).or_default().clone();
+        let loaded = cell.get_or_try_init(|| self.load(key, &rkey, &cell)).await.cloned();
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(&rkey).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            inflight.remove(&rkey);
+        }
+        loaded
+    }
+
+    /// Query Postgres and cache the result, unless the key is invalidated in the meantime
+    async fn load(&self, key: &K, rkey: &str, cell: &Arc<OnceCell<Option<T>>>) -> Result<Option<T>, GenericError> {
+        let client = self.pg.get().await?;
+        let found = postgres::get_opt(&client, &self.query, &self.rowfunc, &[key]).await?;
+        let ttl = match found {
+            Some(_) => self.ttl,
+            None => self.negative_ttl,
+        };
+        if !ttl.is_zero() && self.is_current(rkey, cell) {
+            if let Err(e) = self.store(rkey, &found, ttl).await {
+                println!("ERROR! CacheAside could not write {} to Redis: {}", rkey, e);
+            } else if !self.is_current(rkey, cell) {
+                // invalidated while it was being written, so the invalidation's DEL may have come first
+                if let Err(e) = rediserde::del(&self.redis, rkey).await {
+                    println!("ERROR! CacheAside could not remove stale {} from Redis: {}", rkey, e);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Whether cell is still the load for rkey, i.e. it hasn't been invalidated since the load started
+    fn is_current(&self, rkey: &str, cell: &Arc<OnceCell<Option<T>>>) -> bool {
+        self.inflight.lock().unwrap().get(rkey).is_some_and(|c| Arc::ptr_eq(c, cell))
+    }
+
+    async fn store(&self, rkey: &str, value: &Option<T>, ttl: Duration) -> Result<(), GenericError> {
+        rediserde::set_ex_with(&self.redis, rkey, value, ttl, &self.codec).await
+    }
+
+    /// Cache a row that was just written, so the next read doesn't have to go to Postgres
+    pub async fn put(&self, key: &K, value: &T) -> Result<(), GenericError> {
+        self.store(&self.redis_key(key), &Some(value.clone()), self.ttl).await
+    }
+
+    /// Drop the cached row for key, along with any load in progress, then run the invalidation hooks
+    pub async fn invalidate(&self, key: &K) -> Result<(), GenericError> {
+        let rkey = self.redis_key(key);
+        self.inflight.lock().unwrap().remove(&rkey);
+        rediserde::del(&self.redis, &rkey).await?;
+        for hook in &self.hooks {
+            hook(&rkey);
+        }
+        Ok(())
+    }
+
+    /// Invalidate the keys sent as JSON payloads on a Postgres NOTIFY channel, i.e. from a trigger calling
+    /// pg_notify('user_changed', NEW.id::text), until the cache is dropped
+    pub fn invalidate_on_notify(self: &Arc<Self>, config: &SimpleConfig, channel: &str)
+    where
+        K: DeserializeOwned + Send + 'static,
+        T: 'static,
+        F: 'static,
+        C: 'static,
+    {
+        let cache = Arc::downgrade(self);
+        let mut notifications = Box::pin(postgres::listen::<K>(config, &[channel]));
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.next().await {
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => return,
+                };
+                match notification {
+                    Ok(n) => if let Err(e) = cache.invalidate(&n.payload).await {
+                        println!("ERROR! CacheAside could not invalidate {}: {}", cache.redis_key(&n.payload), e);
+                    },
+                    Err(e) => println!("ERROR! CacheAside got a bad invalidation: {}", e),
+                }
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+    use crate::{postgres::pool_no_tls_from_env, redis::new_pool_from_env};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Thing {
+        id: i64,
+        name: String,
+    }
+
+    #[test]
+    fn cache_aside() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let rpool = new_pool_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_cache;
+                CREATE TABLE _nexum_test_cache (id BIGINT PRIMARY KEY, name TEXT NOT NULL);
+                INSERT INTO _nexum_test_cache VALUES (1, 'one')").await.unwrap();
+            let invalidated = Arc::new(AtomicUsize::new(0));
+            let counter = invalidated.clone();
+            let loads = Arc::new(AtomicUsize::new(0));
+            let loaded = loads.clone();
+            let cache = CacheAside::new(&rpool, &pool, "_nexum_test_cache", "SELECT id, name FROM _nexum_test_cache WHERE id = $1",
+                move |row: &Row| { loaded.fetch_add(1, Ordering::SeqCst); Thing{id: row.get(0), name: row.get(1)} })
+                .on_invalidate(move |_| { counter.fetch_add(1, Ordering::SeqCst); });
+            for id in [1i64, 2] {
+                cache.invalidate(&id).await.unwrap();
+            }
+            // concurrent misses share one load, and both hits and misses are cached
+            let (a, b) = futures::join!(cache.get(&1), cache.get(&1));
+            assert_eq!(a.unwrap(), Some(Thing{id: 1, name: "one".to_string()}));
+            assert_eq!(b.unwrap(), Some(Thing{id: 1, name: "one".to_string()}));
+            assert_eq!(loads.load(Ordering::SeqCst), 1);
+            assert_eq!(cache.get(&2).await.unwrap(), None);
+            client.batch_execute("UPDATE _nexum_test_cache SET name = 'uno'; INSERT INTO _nexum_test_cache VALUES (2, 'two')").await.unwrap();
+            assert_eq!(cache.get(&1).await.unwrap().unwrap().name, "one");
+            assert_eq!(cache.get(&2).await.unwrap(), None);
+            // invalidating picks up the changes
+            cache.invalidate(&1).await.unwrap();
+            cache.invalidate(&2).await.unwrap();
+            assert_eq!(cache.get(&1).await.unwrap().unwrap().name, "uno");
+            assert_eq!(cache.get(&2).await.unwrap().unwrap().name, "two");
+            assert_eq!(invalidated.load(Ordering::SeqCst), 4);
+            assert_eq!(loads.load(Ordering::SeqCst), 3);
+
+            // a load that is running when its key is invalidated doesn't cache the row it read before the write
+            let slow = CacheAside::new(&rpool, &pool, "_nexum_test_cache", "SELECT id, name FROM _nexum_test_cache WHERE id = $1 AND pg_sleep(0.3) IS NOT NULL",
+                |row: &Row| Thing{id: row.get(0), name: row.get(1)});
+            slow.invalidate(&1i64).await.unwrap();
+            let (stale, _) = futures::join!(slow.get(&1), async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                client.execute("UPDATE _nexum_test_cache SET name = 'eins' WHERE id = 1", &[]).await.unwrap();
+                slow.invalidate(&1i64).await.unwrap();
+            });
+            assert_eq!(stale.unwrap().unwrap().name, "uno");
+            assert_eq!(rediserde::get::<Option<Thing>>(&rpool, &slow.redis_key(&1)).await.unwrap(), None);
+            assert_eq!(slow.get(&1).await.unwrap().unwrap().name, "eins");
+
+            // rows can be cached in another format
+            #[cfg(feature = "messagepack")]
+            {
+                let packed = CacheAside::new(&rpool, &pool, "_nexum_test_cache", "SELECT id, name FROM _nexum_test_cache WHERE id = $1",
+                    |row: &Row| Thing{id: row.get(0), name: row.get(1)}).codec(crate::redis::codec::MessagePack);
+                packed.invalidate(&1i64).await.unwrap();
+                assert_eq!(packed.get(&1i64).await.unwrap().unwrap().name, "eins");
+                let raw: Vec<u8> = mobc_redis::redis::AsyncCommands::get(&mut *rpool.get().await.unwrap(), packed.redis_key(&1)).await.unwrap();
+                assert_ne!(raw[0], b'{');
+                assert_eq!(packed.get(&1i64).await.unwrap().unwrap().name, "eins");
+            }
+            cache.invalidate(&1).await.unwrap();
+            cache.invalidate(&2).await.unwrap();
+            client.batch_execute("DROP TABLE _nexum_test_cache").await.unwrap();
+        })
+    }
+}
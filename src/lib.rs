@@ -1,5 +1,6 @@
 pub mod core;
 pub mod hashit;
+pub mod cache;
 pub mod opensearch;
 pub mod postgres;
 pub mod redis;
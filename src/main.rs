@@ -0,0 +1,127 @@
+//! The nexum command line tool. It connects to Postgres using the same PSQL_* environment variables
+//! as postgres::pool_no_tls_from_env(), i.e.
+//! ```text
+//! nexum migrate status --dir migrations
+//! nexum migrate up --dir migrations --dry-run
+//! nexum migrate down --dir migrations --to 3
+//! nexum introspect --schema public users orders --out src/models.rs
+//! ```
+
+use std::{fs, path::PathBuf};
+use structopt::StructOpt;
+use nexum::core::GenericError;
+use nexum::postgres::{self, introspect, migrate};
+
+
+#[derive(StructOpt)]
+#[structopt(name = "nexum", about = "Utilities for services built on nexum")]
+enum Command {
+    /// Apply, revert or list schema migrations
+    Migrate(MigrateCommand),
+    /// Generate Rust structs and row mapping code for tables
+    Introspect {
+        /// The schema the tables are in
+        #[structopt(long, default_value = "public")]
+        schema: String,
+        /// Write the code to this file instead of printing it
+        #[structopt(long, parse(from_os_str))]
+        out: Option<PathBuf>,
+        /// Only list the tables in the schema
+        #[structopt(long)]
+        list: bool,
+        /// The tables to generate code for, or every table in the schema if none are given
+        tables: Vec<String>,
+    },
+}
+
+#[derive(StructOpt)]
+enum MigrateCommand {
+    /// Apply every pending migration
+    Up {
+        /// The directory holding the migration files
+        #[structopt(long, default_value = "migrations", parse(from_os_str))]
+        dir: PathBuf,
+        /// Print what would be applied without changing anything
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Revert applied migrations newer than a version
+    Down {
+        #[structopt(long, default_value = "migrations", parse(from_os_str))]
+        dir: PathBuf,
+        /// Revert every migration with a version greater than this one
+        #[structopt(long)]
+        to: i64,
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// List migrations and whether they have been applied
+    Status {
+        #[structopt(long, default_value = "migrations", parse(from_os_str))]
+        dir: PathBuf,
+    },
+}
+
+
+async fn run_migrate(cmd: MigrateCommand) -> Result<(), GenericError> {
+    let pool = postgres::pool_no_tls_from_env().await?;
+    match cmd {
+        MigrateCommand::Up{dir, dry_run} => {
+            let migrations = migrate::from_dir(dir)?;
+            let verb = if dry_run { "would apply" } else { "applied" };
+            for m in migrate::up(&pool, &migrations, dry_run).await? {
+                println!("{} {} {}", verb, m.version, m.name);
+            }
+        },
+        MigrateCommand::Down{dir, to, dry_run} => {
+            let migrations = migrate::from_dir(dir)?;
+            let verb = if dry_run { "would revert" } else { "reverted" };
+            for m in migrate::down(&pool, &migrations, to, dry_run).await? {
+                println!("{} {} {}", verb, m.version, m.name);
+            }
+        },
+        MigrateCommand::Status{dir} => {
+            let migrations = migrate::from_dir(dir)?;
+            for s in migrate::status(&pool, &migrations).await? {
+                let state = if s.applied { "applied" } else { "pending" };
+                println!("{:>8} {} {}", state, s.version, s.name);
+            }
+        },
+    }
+    Ok(())
+}
+
+
+async fn run_introspect(schema: String, out: Option<PathBuf>, list: bool, tables: Vec<String>) -> Result<(), GenericError> {
+    let pool = postgres::pool_no_tls_from_env().await?;
+    let client = pool.get().await?;
+    let tables = match tables.is_empty() {
+        true => introspect::tables(&client, &schema).await?,
+        false => tables,
+    };
+    if list {
+        for table in tables {
+            println!("{}", table);
+        }
+        return Ok(())
+    }
+    let mut described = Vec::new();
+    for table in &tables {
+        described.push(introspect::describe(&client, &schema, table).await?);
+    }
+    let code = introspect::generate_module(&described);
+    match out {
+        Some(path) => fs::write(path, code)?,
+        None => print!("{}", code),
+    }
+    Ok(())
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), GenericError> {
+    match Command::from_args() {
+        Command::Migrate(cmd) => run_migrate(cmd).await,
+        Command::Introspect{schema, out, list, tables} => run_introspect(schema, out, list, tables).await,
+    }
+}
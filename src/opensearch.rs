@@ -7,8 +7,9 @@ use std::{env, collections::HashMap, vec::Vec};
 use async_trait::async_trait;
 use reqwest;
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
-use serde_json;
-use crate::core::{GenericError};
+use serde_json::{self, json, Value};
+use crate::core::{GenericError, SimpleError};
+use crate::postgres::paginate::{page, Cursor, Page};
 
 /// Just implement this trait on any struct and then you can call .opnsch_upsert() to upsert it!! 
 #[async_trait]
@@ -302,6 +303,82 @@ pub async fn query_payload<TQ: Serialize, T: DeserializeOwned>(index: &str, quer
 
 }
 
+
+/// A hit from a sorted query, whose sort values can be used with search_after
+#[derive(Deserialize)]
+pub struct SortedHit<T> {
+    pub _id: String,
+    pub _index: String,
+    pub _source: T,
+    pub sort: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct SortedHits<T> {
+    pub hits: Vec<SortedHit<T>>,
+}
+
+/// The response to a sorted query. Scores aren't computed when sorting, so they are left out
+#[derive(Deserialize)]
+pub struct SortedResp<T> {
+    pub hits: SortedHits<T>,
+}
+
+/// Flip every direction in a sort clause, which is how a backward cursor pages through search_after
+fn reverse_sort(sort: &Value) -> Result<Value, GenericError> {
+    let flip = |field: &str, order: Option<&str>| {
+        // _score sorts descending by default, everything else ascending
+        let descending = order.unwrap_or(if field == "_score" { "desc" } else { "asc" }) == "desc";
+        if descending { "asc" } else { "desc" }
+    };
+    let mut reversed = Vec::new();
+    for clause in sort.as_array().ok_or_else(|| SimpleError::from_str("sort must be an array"))? {
+        let clause = match clause {
+            Value::String(field) => json!({field.as_str(): flip(field, None)}),
+            Value::Object(map) if map.len() == 1 => {
+                let (field, spec) = map.iter().next().unwrap();
+                match spec {
+                    Value::String(order) => json!({field.as_str(): flip(field, Some(order))}),
+                    Value::Object(options) => {
+                        let mut options = options.clone();
+                        let order = flip(field, options.get("order").and_then(|o| o.as_str()));
+                        options.insert("order".to_string(), Value::from(order));
+                        json!({field.as_str(): options})
+                    },
+                    _ => return Err(SimpleError{message: format!("can't reverse the sort clause {}", clause)}.into()),
+                }
+            },
+            _ => return Err(SimpleError{message: format!("can't reverse the sort clause {}", clause)}.into()),
+        };
+        reversed.push(clause);
+    }
+    Ok(Value::Array(reversed))
+}
+
+/// The body of a search for one page, fetching one extra hit to find out whether there is another page.
+/// sort should end with a unique field so that hits with equal sort values aren't skipped
+pub fn search_page_body(query: &Value, sort: &Value, cursor: Option<&Cursor>, page_size: usize) -> Result<Value, GenericError> {
+    let mut body = json!({"query": query, "sort": sort, "size": page_size + 1});
+    if let Some(cursor) = cursor {
+        if cursor.backward {
+            body["sort"] = reverse_sort(sort)?;
+        }
+        body["search_after"] = Value::from(cursor.values.clone());
+    }
+    Ok(body)
+}
+
+/// Search for a page of documents, keyset paginated with search_after, returning the same Page as postgres::paginate
+pub async fn search_page<T: DeserializeOwned>(index: &str, query: &Value, sort: &Value, cursor: Option<&str>, page_size: usize) -> Result<Page<T>, GenericError> {
+    if page_size == 0 {
+        return Err(SimpleError::from_str("page_size must be at least 1").into())
+    }
+    let cursor = cursor.map(Cursor::decode).transpose()?;
+    let body = search_page_body(query, sort, cursor.as_ref(), page_size)?;
+    let resp: SortedResp<T> = req_payload(Method::Get, &format!("{}/_search", index), &body).await?;
+    page(resp.hits.hits, page_size, cursor.as_ref(), |hit| Ok(hit.sort.clone()), |hit| hit._source)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -470,4 +547,22 @@ mod tests {
         });
         
     }
+
+    #[test]
+    fn test_search_page_body() {
+        let query = json!({"match": {"name": "avocado"}});
+        let sort = json!(["_score", {"date": "desc"}, {"position": {"order": "asc", "missing": "_last"}}]);
+        let body = search_page_body(&query, &sort, None, 10).unwrap();
+        assert_eq!(body, json!({"query": query, "sort": sort, "size": 11}));
+        let cursor = Cursor{values: vec![json!(1.5), json!(1700000000000_i64), json!(7)], backward: true};
+        let body = search_page_body(&query, &sort, Some(&cursor), 10).unwrap();
+        assert_eq!(body["sort"], json!([{"_score": "asc"}, {"date": "asc"}, {"position": {"order": "desc", "missing": "_last"}}]));
+        assert_eq!(body["search_after"], json!([1.5, 1700000000000_i64, 7]));
+        // paging backward from the second page reverses the reversed hits back into order
+        let hits: Vec<SortedHit<i32>> = (1..=3).rev().map(|n| SortedHit{_id: n.to_string(), _index: TEST_INDEX.to_string(), _source: n, sort: vec![json!(n)]}).collect();
+        let page = page(hits, 2, Some(&cursor), |hit| Ok(hit.sort.clone()), |hit| hit._source).unwrap();
+        assert_eq!(page.items, vec![2, 3]);
+        assert_eq!(Cursor::decode(page.prev_cursor.as_ref().unwrap()).unwrap(), Cursor{values: vec![json!(2)], backward: true});
+        assert_eq!(Cursor::decode(page.next_cursor.as_ref().unwrap()).unwrap(), Cursor{values: vec![json!(3)], backward: false});
+    }
 }
@@ -1,10 +1,43 @@
-use std::{env, vec::Vec, error::Error, fmt, marker::Sync};
+use std::{env, vec::Vec, error::Error, fmt, marker::Sync, panic::{self, AssertUnwindSafe}, time::Duration};
+use futures::{future::BoxFuture, FutureExt, Stream, TryStreamExt};
+use rand::Rng;
 pub use tokio_postgres::{Config, NoTls, row::Row, Error as ErrorTKPG};
 use tokio_postgres::{types::ToSql}; // can't pub use ToSql as it is private
-pub use tokio_postgres::GenericClient;
+pub use tokio_postgres::{GenericClient, IsolationLevel, Transaction, error::SqlState};
 pub use mobc::{self, Pool};
 pub use mobc_postgres::PgConnectionManager;
-use crate::core::GenericError;
+use crate::core::{GenericError, SimpleError};
+
+pub mod copy;
+pub use copy::{copy_in, copy_in_stream, copy_out};
+pub mod query;
+pub use query::{QueryBuilder, Op, Order};
+pub mod migrate;
+pub mod listen;
+pub use listen::{listen, notify, Notification};
+pub mod queue;
+pub mod lock;
+pub mod fts;
+pub mod vector;
+pub use vector::{Vector, HalfVector};
+pub mod routing;
+pub use routing::RoutedPool;
+pub mod statements;
+pub use statements::{CachedClient, CachingManager, CacheMetrics, Queryable, STATEMENT_CACHE_SIZE};
+pub mod introspect;
+pub mod paginate;
+pub use paginate::{paginate, Page};
+pub mod outbox;
+pub mod replication;
+pub mod cdc;
+pub use cdc::{Cdc, Change, ChangeEvent};
+
+/// How many times transaction() re-runs a closure after a serialization failure or deadlock
+const TX_MAX_RETRIES: u32 = 5;
+/// The first retry waits this long, doubling with each subsequent attempt
+const TX_BACKOFF_BASE_MS: u64 = 25;
+/// No retry waits longer than this
+const TX_BACKOFF_MAX_MS: u64 = 2000;
 
 
 /// The ConnPool a common connector used for various applications
@@ -13,11 +46,15 @@ use crate::core::GenericError;
 pub type ConnPool = Pool<PgConnectionManager<NoTls>>;
 /// The client is also notls and should be changed in the future
 pub type Client = mobc::Connection<PgConnectionManager<NoTls>>;
+/// A ConnPool whose connections keep their prepared statements, see statements
+pub type CachedPool = Pool<CachingManager>;
+/// A connection from a CachedPool. It derefs to a CachedClient
+pub type CachedConn = mobc::Connection<CachingManager>;
 
 
 /// return an option<T>
-pub async fn get_opt<'a, T>(client: &'a Client, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Option<T>, GenericError> {
-    let rows = client.query(query, params).await?;
+pub async fn get_opt<'a, T, C: Queryable>(client: &'a C, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Option<T>, GenericError> {
+    let rows = client.query_rows(query, params).await?;
     match rows.get(0) {
         None => Ok(None),
         Some(row) => Ok(Some(rowfunc(row))) // see https://users.rust-lang.org/t/how-to-store-function-pointers-in-struct-and-call-them/51348
@@ -25,7 +62,7 @@ pub async fn get_opt<'a, T>(client: &'a Client, query: &'static str, rowfunc: &'
 }
 
 /// return T
-pub async fn get_one<'a, T>(client: &'a Client, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params:&'a [&'a (dyn ToSql + Sync)]) -> Result<T, GenericError> {
+pub async fn get_one<'a, T, C: Queryable>(client: &'a C, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params:&'a [&'a (dyn ToSql + Sync)]) -> Result<T, GenericError> {
     let t: T = match get_opt(client, query, rowfunc, params).await? {
         Some(t) => t,
         None => return Err(MissingRowError{message: format!("No row found for query \"{}\"", query)}.into())
@@ -37,8 +74,8 @@ pub async fn get_one<'a, T>(client: &'a Client, query: &'static str, rowfunc: &'
 /// This cool function takes a references to a pool and a query and returns a vec of results
 /// WHY CAN'T I SHARE BETWEEN THREADS?
 /// see https://stackoverflow.com/questions/71233393/rust-dyn-fn-cannot-be-shared-between-threads-safely
-pub async fn get_vec<'a, T>(client: &'a Client, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params:&'a[&'a(dyn ToSql + Sync)]) -> Result<Vec<T>, GenericError> {
-    let rows = client.query(query, params).await?;
+pub async fn get_vec<'a, T, C: Queryable>(client: &'a C, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params:&'a[&'a(dyn ToSql + Sync)]) -> Result<Vec<T>, GenericError> {
+    let rows = client.query_rows(query, params).await?;
     let mut vt = Vec::new();
     for row in rows {
         let t = rowfunc(&row);
@@ -48,6 +85,199 @@ pub async fn get_vec<'a, T>(client: &'a Client, query: &'static str, rowfunc: &'
 }
 
 
+/// Like get_vec, but returns a stream of results instead of collecting every row into memory first
+pub async fn stream<'a, T, F, C: Queryable>(client: &'a C, query: &'a str, rowfunc: F, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<impl Stream<Item = Result<T, GenericError>>, GenericError>
+where
+    F: Fn(&Row) -> T,
+{
+    let rows = client.query_row_stream(query, params).await?;
+    Ok(rows.map_err(GenericError::from).map_ok(move |row| rowfunc(&row)))
+}
+
+/// Stream results through a server-side cursor, fetching fetch_size rows per round trip.
+/// Unlike stream(), the server only sends the next batch once the previous one is consumed,
+/// so memory stays bounded however slowly the stream is read.
+/// The cursor lives in a transaction that holds the client until the stream is dropped.
+/// fetch_size must be at least 1
+pub async fn stream_cursor<'a, T, F>(client: &'a mut Client, query: &str, fetch_size: i32, rowfunc: F, params: &[&(dyn ToSql + Sync)]) -> Result<impl Stream<Item = Result<T, GenericError>> + 'a, GenericError>
+where
+    T: 'a,
+    F: Fn(&Row) -> T + 'a,
+{
+    if fetch_size < 1 {
+        return Err(SimpleError{message: format!("stream_cursor fetch_size must be at least 1, not {}", fetch_size)}.into())
+    }
+    let tx = client.transaction().await?;
+    let portal = tx.bind(query, params).await?;
+    let batches = futures::stream::try_unfold((tx, portal, false), move |(tx, portal, exhausted)| async move {
+        if exhausted {
+            // dropping the transaction here closes the cursor
+            return Ok(None)
+        }
+        let rows = tx.query_portal(&portal, fetch_size).await?;
+        let exhausted = rows.is_empty() || rows.len() < fetch_size as usize;
+        Ok::<_, GenericError>(Some((rows, (tx, portal, exhausted))))
+    });
+    let rows = batches
+        .map_ok(|rows| futures::stream::iter(rows.into_iter().map(Ok)))
+        .try_flatten()
+        .map_ok(move |row| rowfunc(&row));
+    Ok(rows)
+}
+
+
+/// execute a statement, returning the number of rows affected
+pub async fn execute<'a, C: Queryable>(client: &'a C, query: &'a str, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<u64, GenericError> {
+    let n = client.execute_query(query, params).await?;
+    Ok(n)
+}
+
+/// run an INSERT ... RETURNING statement, mapping the returned row to T
+pub async fn insert_returning<'a, T, C: Queryable>(client: &'a C, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params: &'a [&'a (dyn ToSql + Sync)]) -> Result<T, GenericError> {
+    get_one(client, query, rowfunc, params).await
+}
+
+
+/// Implement this trait on a struct to write it to a table with upsert()
+pub trait ToRow {
+    /// the table the struct is written to
+    fn table() -> &'static str;
+    /// the column names, in the same order as values()
+    fn columns() -> &'static [&'static str];
+    /// the values to be written, in the same order as columns()
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)>;
+}
+
+/// Generate an "INSERT ... ON CONFLICT (conflict_cols) DO UPDATE SET ..." statement
+/// Every column not in conflict_cols is overwritten with the new value.
+/// If there are no other columns, conflicting rows are left alone with DO NOTHING
+pub fn upsert_sql(table: &str, columns: &[&str], conflict_cols: &[&str]) -> String {
+    let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("${}", i)).collect();
+    let updates: Vec<String> = columns.iter()
+        .filter(|col| !conflict_cols.contains(col))
+        .map(|col| format!("{} = EXCLUDED.{}", col, col))
+        .collect();
+    let action = match updates.is_empty() {
+        true => "NOTHING".to_string(),
+        false => format!("UPDATE SET {}", updates.join(", ")),
+    };
+    format!("INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO {}",
+        table, columns.join(", "), placeholders.join(", "), conflict_cols.join(", "), action)
+}
+
+/// upsert a struct implementing ToRow, returning the number of rows affected
+pub async fn upsert<R: ToRow + Sync, C: Queryable>(client: &C, row: &R, conflict_cols: &[&str]) -> Result<u64, GenericError> {
+    let query = upsert_sql(R::table(), R::columns(), conflict_cols);
+    let n = client.execute_query(&query, &row.values()).await?;
+    Ok(n)
+}
+
+/// upsert a struct implementing ToRow, mapping the written row to T via RETURNING *
+/// If conflict_cols covers every column and the row already existed nothing is returned,
+/// which results in a MissingRowError just like get_one
+pub async fn upsert_returning<'a, R: ToRow + Sync, T, C: Queryable>(client: &'a C, row: &'a R, conflict_cols: &[&str], rowfunc: &'a (dyn Fn(&Row) -> T + Sync)) -> Result<T, GenericError> {
+    let query = format!("{} RETURNING *", upsert_sql(R::table(), R::columns(), conflict_cols));
+    let rows = client.query_rows(query.as_str(), &row.values()).await?;
+    match rows.first() {
+        Some(row) => Ok(rowfunc(row)),
+        None => Err(MissingRowError{message: format!("No row returned for query \"{}\"", query)}.into())
+    }
+}
+
+
+/// Run a closure inside a transaction at the given isolation level.
+/// The transaction is committed if the closure returns Ok and rolled back if it returns Err or panics.
+/// If the transaction fails with a serialization failure (40001) or deadlock (40P01),
+/// the whole closure is re-run with exponential backoff, so it should not have side effects outside the database.
+/// Because the closure borrows the transaction, its body needs to be boxed. get_one, execute etc. take &*tx:
+/// ```ignore
+/// let n: i64 = transaction(&pool, IsolationLevel::Serializable, |tx| Box::pin(async move {
+///     get_one(&*tx, "SELECT COUNT(*) FROM things", &|row| row.get(0), &[]).await
+/// })).await?;
+/// ```
+pub async fn transaction<T, F>(pool: &ConnPool, isolation: IsolationLevel, f: F) -> Result<T, GenericError>
+where
+    F: for<'t, 'c> FnMut(&'t mut Transaction<'c>) -> BoxFuture<'t, Result<T, GenericError>>,
+{
+    transaction_retry(pool, isolation, TX_MAX_RETRIES, f).await
+}
+
+/// The same as transaction(), but with a custom number of retries (0 means the closure runs at most once)
+pub async fn transaction_retry<T, F>(pool: &ConnPool, isolation: IsolationLevel, max_retries: u32, mut f: F) -> Result<T, GenericError>
+where
+    F: for<'t, 'c> FnMut(&'t mut Transaction<'c>) -> BoxFuture<'t, Result<T, GenericError>>,
+{
+    let mut client = pool.get().await?;
+    let mut attempt: u32 = 0;
+    loop {
+        let mut tx = client.build_transaction().isolation_level(isolation).start().await?;
+        let result = match AssertUnwindSafe(f(&mut tx)).catch_unwind().await {
+            Ok(result) => result,
+            Err(cause) => {
+                // roll back explicitly so the pooled connection is clean before the panic continues
+                let _ = tx.rollback().await;
+                panic::resume_unwind(cause)
+            }
+        };
+        let result = match result {
+            Ok(t) => tx.commit().await.map(|_| t).map_err(GenericError::from),
+            Err(e) => {
+                let _ = tx.rollback().await;
+                Err(e)
+            }
+        };
+        match result {
+            Err(e) if attempt < max_retries && is_retryable(&e) => {
+                tokio::time::sleep(tx_backoff(attempt)).await;
+                attempt += 1;
+            },
+            other => return other,
+        }
+    }
+}
+
+/// How long to wait before retrying after the given failed attempt: doubling up to TX_BACKOFF_MAX_MS,
+/// less a random amount of up to half so that transactions which conflicted don't retry in lockstep
+fn tx_backoff(attempt: u32) -> Duration {
+    let ceiling = 1u64.checked_shl(attempt).and_then(|factor| TX_BACKOFF_BASE_MS.checked_mul(factor))
+        .unwrap_or(TX_BACKOFF_MAX_MS).min(TX_BACKOFF_MAX_MS);
+    Duration::from_millis(rand::thread_rng().gen_range(ceiling / 2..=ceiling))
+}
+
+/// Run a closure inside a savepoint of an existing transaction.
+/// The savepoint is released on Ok and rolled back to on Err, leaving the outer transaction usable either way,
+/// so savepoint calls can be nested to any depth
+pub async fn savepoint<T, F>(tx: &mut Transaction<'_>, f: F) -> Result<T, GenericError>
+where
+    F: for<'t, 'c> FnOnce(&'t mut Transaction<'c>) -> BoxFuture<'t, Result<T, GenericError>>,
+{
+    let mut sp = tx.transaction().await?;
+    match f(&mut sp).await {
+        Ok(t) => {
+            sp.commit().await?;
+            Ok(t)
+        },
+        Err(e) => {
+            sp.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// True if an error is a serialization failure or deadlock, meaning the transaction can safely be retried
+pub fn is_retryable(e: &GenericError) -> bool {
+    match e.downcast_ref::<ErrorTKPG>() {
+        Some(e) => is_sqlstate(e, &SqlState::T_R_SERIALIZATION_FAILURE) || is_sqlstate(e, &SqlState::T_R_DEADLOCK_DETECTED),
+        None => false,
+    }
+}
+
+/// True if an error was raised by the server with the given SQLSTATE
+pub fn is_sqlstate(e: &ErrorTKPG, state: &SqlState) -> bool {
+    e.code() == Some(state)
+}
+
+
 /// create a new Pool from environment variables
 pub async fn pool_no_tls_from_env() -> Result<ConnPool, GenericError> {
     let config = SimpleConfig::new_from_env();
@@ -56,12 +286,7 @@ pub async fn pool_no_tls_from_env() -> Result<ConnPool, GenericError> {
 
 /// create a new Pool from a SimpleConfig
 pub async fn pool_no_tls_from_config(config: &SimpleConfig) -> Result<ConnPool, GenericError> {
-    let mut pg_config = Config::new();
-    pg_config.user(&config.user);
-    pg_config.password(&config.password);
-    pg_config.dbname(&config.database);
-    pg_config.host(&config.host);
-    pg_config.port(config.port);
+    let pg_config = config.pg_config();
     // instantiate a manager and a pool
     let manager = PgConnectionManager::new(pg_config, NoTls);
     let pool = Pool::builder().max_open(20).max_idle(5).build(manager);
@@ -70,6 +295,21 @@ pub async fn pool_no_tls_from_config(config: &SimpleConfig) -> Result<ConnPool,
     Ok(pool)
 }
 
+/// create a new CachedPool from environment variables
+pub async fn cached_pool_no_tls_from_env() -> Result<CachedPool, GenericError> {
+    let config = SimpleConfig::new_from_env();
+    cached_pool_no_tls_from_config(&config, STATEMENT_CACHE_SIZE).await
+}
+
+/// create a new CachedPool from a SimpleConfig, each connection keeping up to capacity prepared statements
+pub async fn cached_pool_no_tls_from_config(config: &SimpleConfig, capacity: usize) -> Result<CachedPool, GenericError> {
+    let manager = CachingManager::new(config.pg_config(), capacity);
+    let pool = Pool::builder().max_open(20).max_idle(5).build(manager);
+    let _client: CachedConn = pool.get().await?;
+    Ok(pool)
+}
+
+#[derive(Clone)]
 /// This struct describes how to connect to an instance using host/port/passwords etc.
 pub struct SimpleConfig {
     pub host: String,
@@ -106,6 +346,18 @@ impl SimpleConfig {
     }
 
 
+    /// Convert to the Config used by tokio_postgres
+    pub fn pg_config(&self) -> Config {
+        let mut pg_config = Config::new();
+        pg_config.user(&self.user);
+        pg_config.password(&self.password);
+        pg_config.dbname(&self.database);
+        pg_config.host(&self.host);
+        pg_config.port(self.port);
+        pg_config
+    }
+
+
     /// Instantiate a new SimpleConfig purely from environment variables
     pub fn new_from_env() -> Self {
         let user = match env::var("PSQL_USER") {
@@ -158,3 +410,155 @@ impl MissingRowError {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::runtime::Runtime;
+
+    struct Widget {
+        id: i32,
+        name: String,
+        weight: f64,
+    }
+
+    impl ToRow for Widget {
+        fn table() -> &'static str {
+            "_nexum_test_widgets"
+        }
+        fn columns() -> &'static [&'static str] {
+            &["id", "name", "weight"]
+        }
+        fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+            vec![&self.id, &self.name, &self.weight]
+        }
+    }
+
+    #[test]
+    fn test_upsert_sql() {
+        let sql = upsert_sql("widgets", &["id", "name", "weight"], &["id"]);
+        assert_eq!(sql, "INSERT INTO widgets (id, name, weight) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name, weight = EXCLUDED.weight");
+        let sql = upsert_sql("tags", &["a", "b"], &["a", "b"]);
+        assert_eq!(sql, "INSERT INTO tags (a, b) VALUES ($1, $2) ON CONFLICT (a, b) DO NOTHING");
+    }
+
+    #[test]
+    fn write_helpers() {
+        // ensure execute, insert_returning and upsert write what you expect
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_widgets; CREATE TABLE _nexum_test_widgets (id SERIAL PRIMARY KEY, name TEXT NOT NULL, weight FLOAT8 NOT NULL)").await.unwrap();
+            let id: i32 = insert_returning(&client, "INSERT INTO _nexum_test_widgets (name, weight) VALUES ($1, $2) RETURNING id", &|row| row.get(0), &[&"sprocket", &1.5f64]).await.unwrap();
+            let n = execute(&client, "UPDATE _nexum_test_widgets SET weight = weight * 2 WHERE id = $1", &[&id]).await.unwrap();
+            assert_eq!(n, 1);
+            let widget = Widget{id, name: "cog".to_string(), weight: 7.0};
+            let n = upsert(&client, &widget, &["id"]).await.unwrap();
+            assert_eq!(n, 1);
+            let widget = Widget{id: id + 1, name: "gear".to_string(), weight: 2.0};
+            let name: String = upsert_returning(&client, &widget, &["id"], &|row| row.get("name")).await.unwrap();
+            assert_eq!(name, "gear");
+            let names = get_vec(&client, "SELECT name FROM _nexum_test_widgets ORDER BY id", &|row| row.get::<_, String>(0), &[]).await.unwrap();
+            assert_eq!(names, vec!["cog", "gear"]);
+        })
+    }
+
+    #[test]
+    fn stream_rows() {
+        // ensure both streaming methods yield every row in order
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let mut client = pool.get().await.unwrap();
+            let query = "SELECT n FROM generate_series(1, $1) AS n";
+            let rows = stream(&client, query, |row| row.get::<_, i32>(0), &[&2500i32]).await.unwrap();
+            let all: Vec<i32> = rows.try_collect().await.unwrap();
+            assert_eq!(all, (1..=2500).collect::<Vec<i32>>());
+            let rows = stream_cursor(&mut client, query, 100, |row| row.get::<_, i32>(0), &[&2500i32]).await.unwrap();
+            let all: Vec<i32> = rows.try_collect().await.unwrap();
+            assert_eq!(all, (1..=2500).collect::<Vec<i32>>());
+            // a fetch_size of 0 would fetch every row and then empty batches forever
+            assert!(stream_cursor(&mut client, query, 0, |row| row.get::<_, i32>(0), &[&2500i32]).await.is_err());
+            // the client is usable again once the cursor's stream is dropped
+            let one: i32 = get_one(&client, "SELECT 1", &|row| row.get(0), &[]).await.unwrap();
+            assert_eq!(one, 1);
+        })
+    }
+
+    #[test]
+    fn transaction_commit_rollback_savepoint() {
+        // ensure Ok commits, Err rolls back, and a failed savepoint does not poison the outer transaction
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_tx; CREATE TABLE _nexum_test_tx (id INT PRIMARY KEY)").await.unwrap();
+            let _: () = transaction(&pool, IsolationLevel::ReadCommitted, |tx| Box::pin(async move {
+                // the helpers run on a transaction like on a client
+                assert_eq!(execute(&*tx, "INSERT INTO _nexum_test_tx (id) VALUES (1)", &[]).await?, 1);
+                let id: Option<i32> = get_opt(&*tx, "SELECT id FROM _nexum_test_tx", &|row| row.get(0), &[]).await?;
+                assert_eq!(id, Some(1));
+                Ok(())
+            })).await.unwrap();
+            let failed: Result<(), GenericError> = transaction(&pool, IsolationLevel::ReadCommitted, |tx| Box::pin(async move {
+                tx.execute("INSERT INTO _nexum_test_tx (id) VALUES (2)", &[]).await?;
+                Err(MissingRowError::from_str("deliberate").into())
+            })).await;
+            assert!(failed.is_err());
+            let _: () = transaction(&pool, IsolationLevel::ReadCommitted, |tx| Box::pin(async move {
+                tx.execute("INSERT INTO _nexum_test_tx (id) VALUES (3)", &[]).await?;
+                // inserting a duplicate fails inside the savepoint only
+                let dup: Result<u64, GenericError> = savepoint(tx, |sp| Box::pin(async move {
+                    Ok(sp.execute("INSERT INTO _nexum_test_tx (id) VALUES (1)", &[]).await?)
+                })).await;
+                assert!(dup.is_err());
+                tx.execute("INSERT INTO _nexum_test_tx (id) VALUES (4)", &[]).await?;
+                Ok(())
+            })).await.unwrap();
+            let ids = get_vec(&client, "SELECT id FROM _nexum_test_tx ORDER BY id", &|row| row.get::<_, i32>(0), &[]).await.unwrap();
+            assert_eq!(ids, vec![1, 3, 4]);
+        })
+    }
+
+    #[test]
+    fn transaction_retries_serialization_failure() {
+        // the first attempt raises SQLSTATE 40001, which should be retried transparently
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let attempts = AtomicU32::new(0);
+            let n: u32 = transaction(&pool, IsolationLevel::Serializable, |tx| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    if attempt == 0 {
+                        tx.batch_execute("DO $$ BEGIN RAISE EXCEPTION 'conflict' USING ERRCODE = '40001'; END $$").await?;
+                    }
+                    Ok(attempt)
+                })
+            }).await.unwrap();
+            assert_eq!(n, 1);
+            assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+            // however many retries are allowed, the wait stays bounded rather than overflowing
+            for attempt in [0, 1, 10, 63, 64, 1000, u32::MAX] {
+                let backoff = tx_backoff(attempt);
+                assert!(backoff <= Duration::from_millis(TX_BACKOFF_MAX_MS), "{} {:?}", attempt, backoff);
+                assert!(backoff >= Duration::from_millis(TX_BACKOFF_BASE_MS / 2), "{} {:?}", attempt, backoff);
+            }
+            let attempts = AtomicU32::new(0);
+            let n: u32 = transaction_retry(&pool, IsolationLevel::Serializable, u32::MAX, |tx| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    if attempt < 3 {
+                        tx.batch_execute("DO $$ BEGIN RAISE EXCEPTION 'deadlock' USING ERRCODE = '40P01'; END $$").await?;
+                    }
+                    Ok(attempt)
+                })
+            }).await.unwrap();
+            assert_eq!(n, 3);
+        })
+    }
+}
@@ -0,0 +1,653 @@
+//! Change data capture over the logical replication protocol. Changes to the tables in a publication are streamed
+//! from a replication slot as pgoutput messages and decoded into row events, i.e. to keep an OpenSearch index in step with a table:
+//! ```ignore
+//! let cdc = Cdc::new(&SimpleConfig::new_from_env(), "search_sync", "search_tables").await?;
+//! let mut changes = Box::pin(cdc.stream());
+//! while let Some(event) = changes.try_next().await? {
+//!     if let Some(row) = event.change.new_tuple() {
+//!         row.deserialize::<User>()?.opnsch_upsert().await?;
+//!     }
+//! }
+//! ```
+//! The slot is the checkpoint: once every change up to a commit has been handled, a standby status update confirms it
+//! as flushed, and Postgres moves the slot on, so a restarted consumer carries on from there.
+//! Delivery is at least once, since changes handled after the last checkpoint are seen again after a restart.
+//! A slot holds on to WAL until it is checkpointed, so drop slots that are no longer consumed.
+//! The server drops a consumer that hasn't polled within its wal_sender_timeout, the next poll then reconnects.
+//! This needs wal_level = logical, a publication (CREATE PUBLICATION name FOR TABLE ...) and a role with REPLICATION
+use std::{collections::{HashMap, VecDeque}, str::FromStr, sync::Arc, time::{Duration, Instant}};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Number, Value};
+use tokio::sync::Mutex;
+use tokio_postgres::types::{PgLsn, Type};
+use crate::core::{GenericError, SimpleError};
+use super::{listen::quote_ident, SimpleConfig};
+use super::replication::{quote_literal, ReplicationConnection, ReplicationMessage, ServerError, PG_EPOCH_OFFSET_MICROS};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_POLL_INTERVAL_MS: u64 = 500;
+/// How often the server is told how far the consumer has got, well within its wal_sender_timeout (a minute by default)
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+/// SQLSTATE object_in_use, returned while another connection is streaming from a slot
+const SLOT_IN_USE: &str = "55006";
+const SLOT_IN_USE_ATTEMPTS: u32 = 50;
+const SLOT_IN_USE_WAIT: Duration = Duration::from_millis(100);
+
+
+/// A column of a replicated table
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelationColumn {
+    pub name: String,
+    pub type_oid: u32,
+    /// part of the replica identity, usually the primary key
+    pub key: bool,
+}
+
+/// A replicated table, as described by the pgoutput Relation message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub id: u32,
+    pub schema: String,
+    pub name: String,
+    pub columns: Vec<RelationColumn>,
+}
+
+/// A column value. pgoutput sends values in their text representation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Datum {
+    Null,
+    /// an unchanged TOASTed value, which isn't sent again on update
+    Unchanged,
+    Text(String),
+}
+
+/// The column values of a row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuple {
+    pub relation: Arc<Relation>,
+    pub values: Vec<Datum>,
+}
+
+impl Tuple {
+
+    /// The value of a column, or None if there's no such column
+    pub fn get(&self, column: &str) -> Option<&Datum> {
+        let idx = self.relation.columns.iter().position(|c| c.name == column)?;
+        self.values.get(idx)
+    }
+
+    /// Parse a column's text into T, i.e. tuple.parse::<i64>("id"). NULL and unchanged values are None
+    pub fn parse<T: FromStr>(&self, column: &str) -> Result<Option<T>, GenericError> where T::Err: std::fmt::Display {
+        match self.get(column) {
+            Some(Datum::Text(s)) => s.parse().map(Some).map_err(|e| SimpleError{message: format!("column \"{}\": {}", column, e)}.into()),
+            Some(_) => Ok(None),
+            None => Err(SimpleError{message: format!("no column \"{}\" in {}.{}", column, self.relation.schema, self.relation.name)}.into()),
+        }
+    }
+
+    /// The row as a JSON object keyed by column name. Booleans, integers, floats and json columns keep their types,
+    /// everything else is a string, including numeric, which an f64 can't hold exactly. Unchanged values are left out
+    pub fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        for (column, datum) in self.relation.columns.iter().zip(&self.values) {
+            let value = match datum {
+                Datum::Unchanged => continue,
+                Datum::Null => Value::Null,
+                Datum::Text(s) => text_to_json(column.type_oid, s),
+            };
+            map.insert(column.name.clone(), value);
+        }
+        Value::Object(map)
+    }
+
+    /// Deserialize the row via to_json, i.e. into a struct that implements opensearch::UpsertSelf
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, GenericError> {
+        Ok(serde_json::from_value(self.to_json())?)
+    }
+}
+
+fn text_to_json(type_oid: u32, s: &str) -> Value {
+    let number = |n: Option<Number>| n.map(Value::Number).unwrap_or_else(|| Value::from(s));
+    match Type::from_oid(type_oid) {
+        Some(Type::BOOL) => Value::from(s == "t"),
+        Some(Type::INT2 | Type::INT4 | Type::INT8 | Type::OID) => number(s.parse::<i64>().ok().map(Number::from)),
+        Some(Type::FLOAT4 | Type::FLOAT8) => number(s.parse::<f64>().ok().and_then(Number::from_f64)),
+        Some(Type::JSON | Type::JSONB) => serde_json::from_str(s).unwrap_or_else(|_| Value::from(s)),
+        _ => Value::from(s),
+    }
+}
+
+
+/// A change to a row, or a truncation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Insert { new: Tuple },
+    /// old is only sent if the replica identity changed, or the table has REPLICA IDENTITY FULL
+    Update { old: Option<Tuple>, new: Tuple },
+    /// old has the replica identity columns, or every column with REPLICA IDENTITY FULL
+    Delete { old: Tuple },
+    Truncate { relations: Vec<Arc<Relation>> },
+}
+
+impl Change {
+
+    /// The row as it is after an insert or update
+    pub fn new_tuple(&self) -> Option<&Tuple> {
+        match self {
+            Change::Insert{new} | Change::Update{new, ..} => Some(new),
+            _ => None,
+        }
+    }
+
+    /// The table that changed, or the first truncated one
+    pub fn relation(&self) -> Option<&Arc<Relation>> {
+        match self {
+            Change::Insert{new} | Change::Update{new, ..} => Some(&new.relation),
+            Change::Delete{old} => Some(&old.relation),
+            Change::Truncate{relations} => relations.first(),
+        }
+    }
+}
+
+/// A change along with the transaction it was committed in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    /// the end of the commit, which the slot is advanced to once the transaction has been handled
+    pub lsn: PgLsn,
+    pub xid: u32,
+    pub committed_at: DateTime<Utc>,
+    pub change: Change,
+}
+
+
+/// Reads the fields of a pgoutput message
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], GenericError> {
+        if self.buf.len() < n {
+            return Err(SimpleError::from_str("truncated pgoutput message").into())
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, GenericError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, GenericError> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, GenericError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, GenericError> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, GenericError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, GenericError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn cstr(&mut self) -> Result<String, GenericError> {
+        let end = self.buf.iter().position(|b| *b == 0).ok_or_else(|| SimpleError::from_str("unterminated string in pgoutput message"))?;
+        let s = String::from_utf8(self.take(end)?.to_vec())?;
+        self.take(1)?;
+        Ok(s)
+    }
+}
+
+
+/// Turns a sequence of pgoutput messages into ChangeEvents, remembering the relations it has been sent
+#[derive(Default)]
+struct Decoder {
+    relations: HashMap<u32, Arc<Relation>>,
+    /// xid and commit time of the transaction being decoded
+    current: Option<(u32, DateTime<Utc>)>,
+    /// changes of the transaction being decoded, released on commit
+    pending: Vec<Change>,
+}
+
+impl Decoder {
+
+    /// Decode one message, returning the transaction's events once its commit has been decoded
+    fn decode(&mut self, message: &[u8]) -> Result<Option<Vec<ChangeEvent>>, GenericError> {
+        let mut r = Reader{buf: message};
+        match r.u8()? {
+            b'B' => {
+                let _final_lsn = r.u64()?;
+                let committed_at = pg_timestamp(r.i64()?);
+                let xid = r.u32()?;
+                self.current = Some((xid, committed_at));
+                self.pending.clear();
+            },
+            b'C' => {
+                let _flags = r.u8()?;
+                let _commit_lsn = r.u64()?;
+                let lsn = PgLsn::from(r.u64()?);
+                let (xid, committed_at) = self.current.take().ok_or_else(|| SimpleError::from_str("pgoutput commit without a begin"))?;
+                let events = self.pending.drain(..).map(|change| ChangeEvent{lsn, xid, committed_at, change}).collect();
+                return Ok(Some(events))
+            },
+            b'R' => {
+                let id = r.u32()?;
+                let schema = r.cstr()?;
+                let name = r.cstr()?;
+                let _replica_identity = r.u8()?;
+                let mut columns = Vec::new();
+                for _ in 0..r.i16()? {
+                    let flags = r.u8()?;
+                    let name = r.cstr()?;
+                    let type_oid = r.u32()?;
+                    let _typmod = r.i32()?;
+                    columns.push(RelationColumn{name, type_oid, key: flags & 1 == 1});
+                }
+                self.relations.insert(id, Arc::new(Relation{id, schema, name, columns}));
+            },
+            b'I' => {
+                let relation = self.relation(r.u32()?)?;
+                expect(&mut r, b'N')?;
+                let new = tuple(&mut r, relation)?;
+                self.pending.push(Change::Insert{new});
+            },
+            b'U' => {
+                let relation = self.relation(r.u32()?)?;
+                let mut old = None;
+                let mut kind = r.u8()?;
+                if kind == b'K' || kind == b'O' {
+                    old = Some(tuple(&mut r, relation.clone())?);
+                    kind = r.u8()?;
+                }
+                if kind != b'N' {
+                    return Err(SimpleError{message: format!("unexpected tuple type {} in pgoutput update", kind as char)}.into())
+                }
+                let new = tuple(&mut r, relation)?;
+                self.pending.push(Change::Update{old, new});
+            },
+            b'D' => {
+                let relation = self.relation(r.u32()?)?;
+                let _kind = r.u8()?;
+                let old = tuple(&mut r, relation)?;
+                self.pending.push(Change::Delete{old});
+            },
+            b'T' => {
+                let n = r.u32()?;
+                let _options = r.u8()?;
+                let mut relations = Vec::new();
+                for _ in 0..n {
+                    relations.push(self.relation(r.u32()?)?);
+                }
+                self.pending.push(Change::Truncate{relations});
+            },
+            // Origin, Type and Message don't describe row changes
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    fn relation(&self, id: u32) -> Result<Arc<Relation>, GenericError> {
+        match self.relations.get(&id) {
+            Some(relation) => Ok(relation.clone()),
+            None => Err(SimpleError{message: format!("pgoutput change for relation {} before its Relation message", id)}.into()),
+        }
+    }
+}
+
+fn expect(r: &mut Reader, kind: u8) -> Result<(), GenericError> {
+    match r.u8()? {
+        k if k == kind => Ok(()),
+        k => Err(SimpleError{message: format!("expected tuple type {} in pgoutput message but got {}", kind as char, k as char)}.into()),
+    }
+}
+
+fn tuple(r: &mut Reader, relation: Arc<Relation>) -> Result<Tuple, GenericError> {
+    let mut values = Vec::new();
+    for _ in 0..r.i16()? {
+        let datum = match r.u8()? {
+            b'n' => Datum::Null,
+            b'u' => Datum::Unchanged,
+            b't' => {
+                let len = r.i32()?;
+                Datum::Text(String::from_utf8(r.take(len as usize)?.to_vec())?)
+            },
+            k => return Err(SimpleError{message: format!("unexpected column kind {} in pgoutput tuple", k as char)}.into()),
+        };
+        values.push(datum);
+    }
+    Ok(Tuple{relation, values})
+}
+
+fn pg_timestamp(micros: i64) -> DateTime<Utc> {
+    Utc.timestamp_micros(micros + PG_EPOCH_OFFSET_MICROS).single().unwrap_or_default()
+}
+
+
+/// Consumes changes to the tables in a publication through a logical replication slot
+pub struct Cdc {
+    config: SimpleConfig,
+    slot: String,
+    publication: String,
+    batch_size: usize,
+    poll_interval: Duration,
+    state: Mutex<State>,
+}
+
+/// The replication connection and how far it has got
+struct State {
+    /// None until replication has been started, and again after the connection failed
+    conn: Option<ReplicationConnection>,
+    decoder: Decoder,
+    /// the end of the WAL received so far
+    received: PgLsn,
+    /// the checkpoint, which is reported to the server as flushed
+    flushed: PgLsn,
+    /// the LSN of the last batch poll() returned
+    returned: PgLsn,
+    last_status: Instant,
+}
+
+impl Cdc {
+
+    /// Instantiate a consumer and start replication, creating the slot if it doesn't exist yet.
+    /// A new slot only sees changes made after it was created
+    pub async fn new(config: &SimpleConfig, slot: &str, publication: &str) -> Result<Self, GenericError> {
+        let mut conn = ReplicationConnection::connect(config).await?;
+        let existing = conn.simple_query(&format!("SELECT 1 FROM pg_replication_slots WHERE slot_name = {}", quote_literal(slot))).await?;
+        if existing.is_empty() {
+            conn.simple_query(&format!("CREATE_REPLICATION_SLOT {} LOGICAL pgoutput", quote_ident(slot))).await?;
+        }
+        start_replication(&mut conn, slot, publication).await?;
+        let lsn = PgLsn::from(0);
+        Ok(Cdc {
+            config: config.clone(),
+            slot: slot.to_string(),
+            publication: publication.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            state: Mutex::new(State{conn: Some(conn), decoder: Decoder::default(), received: lsn, flushed: lsn, returned: lsn, last_status: Instant::now()}),
+        })
+    }
+
+    /// Roughly how many changes poll() returns at once. Transactions aren't split, so a large one can exceed this
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long poll() waits for a transaction to be committed before returning without one
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Read the next batch of committed transactions from the replication stream, returning their changes and the LSN
+    /// to checkpoint once they have been handled (None if nothing was committed within the poll interval).
+    /// If the connection fails it is reopened by the next poll, which starts again from the checkpoint,
+    /// so changes returned since are seen again
+    pub async fn poll(&self) -> Result<(Vec<ChangeEvent>, Option<PgLsn>), GenericError> {
+        let mut state = self.state.lock().await;
+        let result = self.read_batch(&mut state).await;
+        if result.is_err() {
+            state.conn = None;
+            state.decoder = Decoder::default();
+        }
+        result
+    }
+
+    async fn read_batch(&self, state: &mut State) -> Result<(Vec<ChangeEvent>, Option<PgLsn>), GenericError> {
+        let State{conn, decoder, received, flushed, returned, last_status} = state;
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let mut reopened = ReplicationConnection::connect(&self.config).await?;
+                start_replication(&mut reopened, &self.slot, &self.publication).await?;
+                conn.insert(reopened)
+            },
+        };
+        let deadline = Instant::now() + self.poll_interval;
+        let mut events = Vec::new();
+        let mut lsn = None;
+        loop {
+            // once a transaction has been read, only take what has already arrived
+            let wait = match lsn {
+                Some(_) if events.len() >= self.batch_size => break,
+                Some(_) => Duration::ZERO,
+                None => deadline.saturating_duration_since(Instant::now()),
+            };
+            let message = match tokio::time::timeout(wait, conn.recv()).await {
+                Ok(message) => message?,
+                Err(_) => break,
+            };
+            match message {
+                ReplicationMessage::XLogData{wal_end, data, ..} => {
+                    *received = (*received).max(wal_end);
+                    if let Some(committed) = decoder.decode(&data)? {
+                        // transactions that didn't touch the publication's tables still move the checkpoint on
+                        lsn = Some(match committed.first() {
+                            Some(event) => event.lsn,
+                            None => commit_lsn(&data)?,
+                        });
+                        events.extend(committed);
+                    }
+                },
+                ReplicationMessage::Keepalive{wal_end, reply} => {
+                    *received = (*received).max(wal_end);
+                    // with everything handled and nothing half read, the WAL up to here holds nothing for us,
+                    // so confirm it and let Postgres recycle it
+                    if *returned <= *flushed && lsn.is_none() && decoder.current.is_none() {
+                        *flushed = (*flushed).max(wal_end);
+                    }
+                    if reply {
+                        conn.send_status(*received, *flushed, false).await?;
+                        *last_status = Instant::now();
+                    }
+                },
+            }
+        }
+        if last_status.elapsed() >= STATUS_INTERVAL {
+            conn.send_status(*received, *flushed, false).await?;
+            *last_status = Instant::now();
+        }
+        if let Some(lsn) = lsn {
+            *returned = lsn;
+        }
+        Ok((events, lsn))
+    }
+
+    /// Mark every change up to lsn as handled. The server is told with a standby status update, and moves the slot's
+    /// confirmed position on when it processes it, so checkpointed() catches up shortly after
+    pub async fn checkpoint(&self, lsn: PgLsn) -> Result<(), GenericError> {
+        let mut state = self.state.lock().await;
+        state.flushed = state.flushed.max(lsn);
+        let (received, flushed) = (state.received.max(lsn), state.flushed);
+        if let Some(conn) = state.conn.as_mut() {
+            if let Err(e) = conn.send_status(received, flushed, false).await {
+                state.conn = None;
+                state.decoder = Decoder::default();
+                return Err(e)
+            }
+            state.last_status = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// The position the slot has been checkpointed to, according to the server
+    pub async fn checkpointed(&self) -> Result<Option<PgLsn>, GenericError> {
+        let mut conn = ReplicationConnection::connect(&self.config).await?;
+        let rows = conn.simple_query(&format!("SELECT confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = {}", quote_literal(&self.slot))).await?;
+        conn.close().await;
+        match rows.into_iter().next().and_then(|row| row.into_iter().next().flatten()) {
+            Some(lsn) => Ok(Some(lsn.parse().map_err(|_| SimpleError{message: format!("invalid LSN {}", lsn)})?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stop replication and drop the slot, so Postgres no longer keeps WAL for it
+    pub async fn drop_slot(self) -> Result<(), GenericError> {
+        if let Some(conn) = self.state.into_inner().conn {
+            conn.close().await;
+        }
+        let mut conn = ReplicationConnection::connect(&self.config).await?;
+        let sql = format!("DROP_REPLICATION_SLOT {}", quote_ident(&self.slot));
+        let mut attempts = 1;
+        loop {
+            match conn.simple_query(&sql).await {
+                Err(e) if attempts < SLOT_IN_USE_ATTEMPTS && slot_in_use(&e) => tokio::time::sleep(SLOT_IN_USE_WAIT).await,
+                result => {
+                    conn.close().await;
+                    return result.map(|_| ())
+                },
+            }
+            attempts += 1;
+        }
+    }
+
+    /// Stream changes as they are committed. A batch is checkpointed when the next one is requested,
+    /// i.e. once every change in it has been taken from the stream
+    pub fn stream(self) -> impl Stream<Item = Result<ChangeEvent, GenericError>> {
+        futures::stream::try_unfold((self, VecDeque::new(), None), |(cdc, mut buffered, mut handled): (Cdc, VecDeque<ChangeEvent>, Option<PgLsn>)| async move {
+            loop {
+                if let Some(event) = buffered.pop_front() {
+                    return Ok(Some((event, (cdc, buffered, handled))))
+                }
+                if let Some(lsn) = handled.take() {
+                    cdc.checkpoint(lsn).await?;
+                }
+                let (events, lsn) = cdc.poll().await?;
+                handled = lsn;
+                buffered.extend(events);
+            }
+        })
+    }
+}
+
+/// START_REPLICATION from the slot's confirmed position. The walsender of a consumer that has just gone away
+/// can hold on to the slot for a moment, so that is waited out
+async fn start_replication(conn: &mut ReplicationConnection, slot: &str, publication: &str) -> Result<(), GenericError> {
+    let mut attempts = 1;
+    loop {
+        match conn.start_logical(slot, publication, PgLsn::from(0)).await {
+            Err(e) if attempts < SLOT_IN_USE_ATTEMPTS && slot_in_use(&e) => tokio::time::sleep(SLOT_IN_USE_WAIT).await,
+            result => return result,
+        }
+        attempts += 1;
+    }
+}
+
+fn slot_in_use(e: &GenericError) -> bool {
+    e.downcast_ref::<ServerError>().map(|e| e.code == SLOT_IN_USE) == Some(true)
+}
+
+/// The end LSN of a Commit message
+fn commit_lsn(message: &[u8]) -> Result<PgLsn, GenericError> {
+    let mut r = Reader{buf: message};
+    r.take(1 + 1 + 8)?;
+    Ok(PgLsn::from(r.u64()?))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use serde::Deserialize;
+    use tokio::runtime::Runtime;
+    use crate::postgres::pool_no_tls_from_config;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Thing {
+        id: i64,
+        name: Option<String>,
+        score: String,
+        active: bool,
+    }
+
+    /// Poll until n changes have been read, returning them with the LSN of the last batch
+    async fn poll_changes(cdc: &Cdc, n: usize) -> (Vec<ChangeEvent>, Option<PgLsn>) {
+        let mut events = Vec::new();
+        let mut lsn = None;
+        for _ in 0..50 {
+            let (batch, batch_lsn) = cdc.poll().await.unwrap();
+            events.extend(batch);
+            lsn = batch_lsn.or(lsn);
+            if events.len() >= n {
+                break
+            }
+        }
+        (events, lsn)
+    }
+
+    #[test]
+    fn capture_changes() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = SimpleConfig::new_from_env();
+            let pool = pool_no_tls_from_config(&config).await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_cdc;
+                CREATE TABLE _nexum_test_cdc (id BIGINT PRIMARY KEY, name TEXT, score NUMERIC, active BOOLEAN NOT NULL);
+                DROP PUBLICATION IF EXISTS _nexum_test_cdc_pub;
+                CREATE PUBLICATION _nexum_test_cdc_pub FOR TABLE _nexum_test_cdc;
+                SELECT pg_drop_replication_slot(slot_name) FROM pg_replication_slots WHERE slot_name = '_nexum_test_cdc_slot'").await.unwrap();
+            let open = || async { Cdc::new(&config, "_nexum_test_cdc_slot", "_nexum_test_cdc_pub").await.unwrap().poll_interval(Duration::from_millis(200)) };
+            let cdc = open().await;
+            client.execute("INSERT INTO _nexum_test_cdc VALUES (1, 'one', 12345678901234567.89, true), (2, NULL, 2, false)", &[]).await.unwrap();
+            client.execute("UPDATE _nexum_test_cdc SET name = 'uno' WHERE id = 1", &[]).await.unwrap();
+            client.execute("DELETE FROM _nexum_test_cdc WHERE id = 2", &[]).await.unwrap();
+            let (events, lsn) = poll_changes(&cdc, 4).await;
+            assert_eq!(events.len(), 4);
+            let row = events[0].change.new_tuple().unwrap();
+            assert_eq!((row.relation.name.as_str(), row.relation.columns[0].key), ("_nexum_test_cdc", true));
+            assert_eq!(row.deserialize::<Thing>().unwrap(), Thing{id: 1, name: Some("one".to_string()), score: "12345678901234567.89".to_string(), active: true});
+            assert_eq!(events[1].change.new_tuple().unwrap().get("name"), Some(&Datum::Null));
+            assert!(matches!(&events[2].change, Change::Update{old: None, new} if new.parse::<String>("name").unwrap().as_deref() == Some("uno")));
+            match &events[3].change {
+                Change::Delete{old} => assert_eq!(old.parse::<i64>("id").unwrap(), Some(2)),
+                other => panic!("expected a delete, got {:?}", other),
+            }
+            assert!(events[0].lsn == events[1].lsn && events[1].lsn < events[2].lsn);
+
+            // a restarted consumer sees everything after the checkpoint again
+            drop(cdc);
+            let cdc = open().await;
+            assert_eq!(poll_changes(&cdc, 4).await.0, events);
+            cdc.checkpoint(lsn.unwrap()).await.unwrap();
+            let mut checkpointed = None;
+            for _ in 0..50 {
+                checkpointed = cdc.checkpointed().await.unwrap();
+                if checkpointed >= lsn {
+                    break
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            assert!(checkpointed >= lsn);
+            drop(cdc);
+            let cdc = open().await;
+            assert!(cdc.poll().await.unwrap().0.is_empty());
+
+            // the stream checkpoints batches as they are consumed
+            let mut stream = Box::pin(cdc.stream());
+            client.batch_execute("INSERT INTO _nexum_test_cdc VALUES (3, 'three', 3, true); TRUNCATE _nexum_test_cdc").await.unwrap();
+            let event = tokio::time::timeout(Duration::from_secs(5), stream.try_next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(event.change.new_tuple().unwrap().parse::<i64>("id").unwrap(), Some(3));
+            let event = tokio::time::timeout(Duration::from_secs(5), stream.try_next()).await.unwrap().unwrap().unwrap();
+            assert!(matches!(event.change, Change::Truncate{..}));
+            drop(stream);
+            open().await.drop_slot().await.unwrap();
+            client.batch_execute("DROP PUBLICATION _nexum_test_cdc_pub; DROP TABLE _nexum_test_cdc").await.unwrap();
+        })
+    }
+}
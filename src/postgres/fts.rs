@@ -0,0 +1,258 @@
+//! Full text search helpers. ts_query() turns what a user types into a tsquery string that
+//! to_tsquery() always accepts, supporting a small search-box syntax:
+//!   crimson thread      both words (AND)
+//!   crimson OR scarlet  either word (| also works)
+//!   "crimson thread"    the words next to each other, in order
+//!   -thread             without the word (also works on "phrases")
+//!   thre*               words starting with a prefix
+//! Every word is quoted, so characters that mean something to to_tsquery (& | ! : ( ) etc.) can't break the query.
+//! SearchQuery then builds ranked search SQL using ts_rank_cd and, optionally, ts_headline snippets
+
+use std::marker::Sync;
+use tokio_postgres::types::ToSql;
+use crate::core::{GenericError, SimpleError};
+use super::{get_vec, query::is_identifier, Client, Row};
+
+
+/// A word or phrase parsed from a search box
+#[derive(Debug, PartialEq)]
+struct Term {
+    words: Vec<String>,
+    negated: bool,
+    prefix: bool,
+}
+
+impl Term {
+    fn to_tsquery(&self) -> String {
+        let lexemes: Vec<String> = self.words.iter().map(|w| quote_lexeme(w)).collect();
+        let mut s = lexemes.join(" <-> ");
+        if self.prefix {
+            s.push_str(":*");
+        }
+        if self.words.len() > 1 {
+            s = format!("({})", s);
+        }
+        match self.negated {
+            true => format!("!{}", s),
+            false => s,
+        }
+    }
+}
+
+/// quote a lexeme for to_tsquery, escaping quotes and backslashes
+fn quote_lexeme(word: &str) -> String {
+    format!("'{}'", word.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// Split a search box into clauses which are OR-ed together, each a list of terms which are AND-ed together
+fn parse(input: &str) -> Vec<Vec<Term>> {
+    let mut clauses: Vec<Vec<Term>> = vec![Vec::new()];
+    let mut chars = input.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let negated = match chars.peek() {
+            None => break,
+            Some('-') => {
+                chars.next();
+                true
+            },
+            Some(_) => false,
+        };
+        let term = match chars.peek() {
+            Some('"') => {
+                chars.next();
+                let phrase: String = chars.by_ref().take_while(|c| *c != '"').collect();
+                Term{words: phrase.split_whitespace().map(|w| w.to_string()).collect(), negated, prefix: false}
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() || *c == '"' {
+                        break
+                    }
+                    word.push(*c);
+                    chars.next();
+                }
+                if !negated && (word == "OR" || word == "|") {
+                    clauses.push(Vec::new());
+                    continue
+                }
+                let prefix = word.ends_with('*');
+                let word = word.trim_end_matches('*').to_string();
+                match word.is_empty() {
+                    true => Term{words: Vec::new(), negated, prefix},
+                    false => Term{words: vec![word], negated, prefix},
+                }
+            },
+        };
+        if !term.words.is_empty() {
+            clauses.last_mut().unwrap().push(term);
+        }
+    }
+    clauses.into_iter().filter(|c| !c.is_empty()).collect()
+}
+
+/// Convert a search box query to a tsquery string. An empty string means there was nothing to search for
+pub fn ts_query(input: &str) -> String {
+    let clauses: Vec<String> = parse(input).iter().map(|terms| {
+        let terms: Vec<String> = terms.iter().map(Term::to_tsquery).collect();
+        terms.join(" & ")
+    }).collect();
+    match clauses.len() {
+        0 | 1 => clauses.join(""),
+        _ => clauses.iter().map(|c| format!("({})", c)).collect::<Vec<String>>().join(" | "),
+    }
+}
+
+
+/// Where the tsvector being searched comes from
+enum Vector {
+    /// a stored tsvector column
+    Column(String),
+    /// a text column converted with to_tsvector
+    Text(String),
+}
+
+
+/// Builds ranked full text search SQL. The rows it returns have the selected columns
+/// plus a "rank" column (REAL) and, if a headline column was given, a "headline" column (TEXT)
+pub struct SearchQuery {
+    table: String,
+    config: String,
+    columns: Vec<String>,
+    vector: Option<Vector>,
+    headline: Option<(String, String)>,
+    limit: i64,
+}
+
+impl SearchQuery {
+
+    /// Search a table using a text search configuration such as "english" or "simple"
+    pub fn new(table: &str, config: &str) -> Self {
+        SearchQuery {
+            table: table.to_string(),
+            config: config.to_string(),
+            columns: Vec::new(),
+            vector: None,
+            headline: None,
+            limit: 20,
+        }
+    }
+
+    /// The columns to return
+    pub fn select(mut self, columns: &[&str]) -> Self {
+        self.columns = columns.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// Search a stored (and ideally GIN indexed) tsvector column
+    pub fn vector_column(mut self, column: &str) -> Self {
+        self.vector = Some(Vector::Column(column.to_string()));
+        self
+    }
+
+    /// Search a text column, converting it with to_tsvector on the fly
+    pub fn text_column(mut self, column: &str) -> Self {
+        self.vector = Some(Vector::Text(column.to_string()));
+        self
+    }
+
+    /// Return a snippet of a text column with the matches highlighted.
+    /// options are passed to ts_headline, i.e. "StartSel=<b>, StopSel=</b>, MaxWords=35"
+    pub fn headline(mut self, column: &str, options: &str) -> Self {
+        self.headline = Some((column.to_string(), options.to_string()));
+        self
+    }
+
+    /// Return at most this many rows
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// The SQL, which takes the regconfig as $1, the tsquery string as $2 and the headline options (if any) as $3
+    pub fn sql(&self) -> Result<String, GenericError> {
+        let (vector_column, vector) = match &self.vector {
+            Some(Vector::Column(c)) => (c, c.clone()),
+            Some(Vector::Text(c)) => (c, format!("to_tsvector($1::text::regconfig, {})", c)),
+            None => return Err(SimpleError::from_str("SearchQuery needs a vector_column or text_column").into()),
+        };
+        let mut identifiers: Vec<&str> = vec![&self.table, &self.config, vector_column];
+        identifiers.extend(self.columns.iter().map(|c| c.as_str()));
+        if let Some((column, _)) = &self.headline {
+            identifiers.push(column);
+        }
+        if let Some(bad) = identifiers.iter().find(|i| !is_identifier(i)) {
+            return Err(SimpleError{message: format!("\"{}\" is not a valid identifier", bad)}.into())
+        }
+        let mut select = self.columns.clone();
+        select.push(format!("ts_rank_cd({}, query) AS rank", vector));
+        if let Some((column, _)) = &self.headline {
+            select.push(format!("ts_headline($1::text::regconfig, {}, query, $3) AS headline", column));
+        }
+        Ok(format!("SELECT {} FROM {}, to_tsquery($1::text::regconfig, $2) AS query WHERE {} @@ query ORDER BY rank DESC LIMIT {}",
+            select.join(", "), self.table, vector, self.limit))
+    }
+}
+
+/// Run a search for what a user typed, mapping each row with rowfunc
+pub async fn search<'a, T>(client: &'a Client, search: &SearchQuery, input: &str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync)) -> Result<Vec<T>, GenericError> {
+    let tsquery = ts_query(input);
+    if tsquery.is_empty() {
+        return Ok(Vec::new())
+    }
+    let sql = search.sql()?;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&search.config, &tsquery];
+    if let Some((_, options)) = &search.headline {
+        params.push(options);
+    }
+    get_vec(client, &sql, rowfunc, &params).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::pool_no_tls_from_env;
+
+    #[test]
+    fn parse_search_box() {
+        assert_eq!(ts_query("crimson thread"), "'crimson' & 'thread'");
+        assert_eq!(ts_query("crimson OR scarlet thread"), "('crimson') | ('scarlet' & 'thread')");
+        assert_eq!(ts_query("\"crimson thread\" -needle thre*"), "('crimson' <-> 'thread') & !'needle' & 'thre':*");
+        assert_eq!(ts_query("-\"red herring\""), "!('red' <-> 'herring')");
+        assert_eq!(ts_query("foo & bar a:b it's"), "'foo' & '&' & 'bar' & 'a:b' & 'it''s'");
+        assert_eq!(ts_query("  OR - * \"\" "), "");
+    }
+
+    #[test]
+    fn run_search() {
+        // ensure awkward input is accepted by to_tsquery, and results are ranked with headlines
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            for input in ["foo & bar", "a:b", "(((", "it's \\ fine", "!!!", "\"unclosed phrase", "x* OR -y"] {
+                let tsquery = ts_query(input);
+                client.query("SELECT to_tsquery('english', $1)", &[&tsquery]).await.unwrap();
+            }
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_fts; CREATE TABLE _nexum_test_fts (id INT, body TEXT);
+                INSERT INTO _nexum_test_fts VALUES (1, 'The crimson thread runs through the tapestry'),
+                (2, 'A crimson sky and a loose thread'), (3, 'Nothing to see here')").await.unwrap();
+            let query = SearchQuery::new("_nexum_test_fts", "english")
+                .select(&["id"])
+                .text_column("body")
+                .headline("body", "StartSel=[, StopSel=]");
+            let hits = search(&client, &query, "\"crimson thread\"", &|row| (row.get::<_, i32>("id"), row.get::<_, String>("headline"))).await.unwrap();
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].0, 1);
+            assert!(hits[0].1.contains("[crimson] [thread]"));
+            let hits = search(&client, &query, "crimson -tapestry", &|row| row.get::<_, i32>("id")).await.unwrap();
+            assert_eq!(hits, vec![2]);
+            assert!(SearchQuery::new("_nexum_test_fts", "english").text_column("body); DROP TABLE x; --").sql().is_err());
+        })
+    }
+}
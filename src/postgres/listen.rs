@@ -0,0 +1,189 @@
+//! LISTEN/NOTIFY support, so services can react to changes instead of polling tables.
+//! listen() holds its own connection (outside any pool) in a background task,
+//! reconnecting and re-issuing LISTEN whenever that connection is lost.
+//! Postgres does not queue notifications for a disconnected listener,
+//! so anything sent while reconnecting is missed; treat notifications as hints to go and look
+
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json;
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, NoTls};
+use crate::core::GenericError;
+use super::{ConnPool, SimpleConfig};
+
+/// how many notifications are buffered before the listener waits for the stream to be read
+const LISTEN_BUFFER: usize = 1024;
+/// the longest the listener waits between reconnection attempts
+const LISTEN_MAX_BACKOFF_SECONDS: u64 = 30;
+
+
+/// A notification with its payload deserialized
+#[derive(Debug, Clone)]
+pub struct Notification<T> {
+    pub channel: String,
+    /// the process id of the backend that sent the notification
+    pub process_id: i32,
+    pub payload: T,
+}
+
+
+/// quote a channel name so LISTEN matches pg_notify exactly, including case
+pub(crate) fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Listen on some channels, returning a stream of notifications whose payloads are deserialized from JSON.
+/// A payload that can't be deserialized yields an Err item, but the stream carries on.
+/// The background task and its connection are closed as soon as the stream is dropped
+pub fn listen<T>(config: &SimpleConfig, channels: &[&str]) -> impl Stream<Item = Result<Notification<T>, GenericError>>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let pg_config = config.pg_config();
+    let listen_sql: String = channels.iter().map(|c| format!("LISTEN {};", quote_ident(c))).collect();
+    let (sender, mut receiver) = mpsc::channel(LISTEN_BUFFER);
+    tokio::spawn(async move {
+        let mut backoff = 1;
+        loop {
+            let connected = tokio::select! {
+                _ = sender.closed() => return,
+                connected = pg_config.connect(NoTls) => connected,
+            };
+            let (client, mut connection) = match connected {
+                Ok(conn) => conn,
+                Err(e) => {
+                    println!("ERROR! listen() could not connect, retrying in {}s: {}", backoff, e);
+                    if !wait_unless_closed(&sender, backoff).await {
+                        return
+                    }
+                    backoff = (backoff * 2).min(LISTEN_MAX_BACKOFF_SECONDS);
+                    continue
+                }
+            };
+            // the connection has to be polled for the LISTEN to complete, so forward messages in another task
+            let forward_to = sender.clone();
+            let forward = tokio::spawn(async move {
+                let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+                loop {
+                    let message = tokio::select! {
+                        // the stream was dropped, so stop even if the channel is idle
+                        _ = forward_to.closed() => return false,
+                        message = messages.next() => message,
+                    };
+                    let n = match message {
+                        Some(Ok(AsyncMessage::Notification(n))) => n,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => {
+                            println!("ERROR! listen() lost its connection: {}", e);
+                            return true
+                        },
+                        None => return true,
+                    };
+                    let payload = serde_json::from_str::<T>(n.payload()).map_err(GenericError::from);
+                    let item = payload.map(|payload| Notification{channel: n.channel().to_string(), process_id: n.process_id(), payload});
+                    if forward_to.send(item).await.is_err() {
+                        return false
+                    }
+                }
+            });
+            let reconnect = match client.batch_execute(&listen_sql).await {
+                Ok(()) => {
+                    backoff = 1;
+                    // keep the client alive until the connection ends
+                    forward.await.unwrap_or(true)
+                },
+                Err(e) => {
+                    // nothing would ever arrive on this connection, so drop it and start again
+                    println!("ERROR! listen() could not LISTEN, reconnecting in {}s: {}", backoff, e);
+                    forward.abort();
+                    true
+                },
+            };
+            drop(client);
+            if !reconnect || !wait_unless_closed(&sender, backoff).await {
+                return
+            }
+            backoff = (backoff * 2).min(LISTEN_MAX_BACKOFF_SECONDS);
+        }
+    });
+    futures::stream::poll_fn(move |cx| receiver.poll_recv(cx))
+}
+
+/// Sleep for some seconds, returning false straight away if the stream is dropped meanwhile
+async fn wait_unless_closed<T>(sender: &mpsc::Sender<T>, seconds: u64) -> bool {
+    tokio::select! {
+        _ = sender.closed() => false,
+        _ = tokio::time::sleep(Duration::from_secs(seconds)) => true,
+    }
+}
+
+
+/// Send a notification whose payload is serialized to JSON
+/// Postgres limits payloads to just under 8000 bytes
+pub async fn notify<T: Serialize>(pool: &ConnPool, channel: &str, payload: &T) -> Result<(), GenericError> {
+    let client = pool.get().await?;
+    let jz = serde_json::to_string(payload)?;
+    client.execute("SELECT pg_notify($1, $2)", &[&channel, &jz]).await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use tokio::runtime::Runtime;
+    use crate::postgres::pool_no_tls_from_config;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct RowChanged {
+        table: String,
+        id: i32,
+    }
+
+    #[test]
+    fn listen_notify_reconnect() {
+        // ensure notifications arrive, and keep arriving after the listening connection is killed
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = SimpleConfig::new_from_env();
+            let pool = pool_no_tls_from_config(&config).await.unwrap();
+            let mut notifications = Box::pin(listen::<RowChanged>(&config, &["_nexum_Test_Channel"]));
+            // keep notifying until the listener is ready, then until it has reconnected
+            for id in 1..=3 {
+                let expected = RowChanged{table: "widgets".to_string(), id};
+                let received = loop {
+                    notify(&pool, "_nexum_Test_Channel", &expected).await.unwrap();
+                    if let Ok(Some(n)) = tokio::time::timeout(Duration::from_millis(200), notifications.next()).await {
+                        break n.unwrap()
+                    }
+                };
+                assert_eq!(received.channel, "_nexum_Test_Channel");
+                assert_eq!(received.payload, expected);
+                if id == 3 {
+                    break
+                }
+                let client = pool.get().await.unwrap();
+                client.execute("SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE query LIKE 'LISTEN \"_nexum_Test_Channel\"%'", &[]).await.unwrap();
+                // drain anything sent more than once before the listener was killed
+                while let Ok(Some(_)) = tokio::time::timeout(Duration::from_millis(50), notifications.next()).await {}
+            }
+            // dropping the stream closes the reconnected listener's connection, even though nothing is being sent
+            drop(notifications);
+            let client = pool.get().await.unwrap();
+            let listening = "SELECT COUNT(*) FROM pg_stat_activity WHERE query LIKE 'LISTEN \"_nexum_Test_Channel\"%'";
+            let mut closed = false;
+            for _ in 0..50 {
+                let n: i64 = client.query_one(listening, &[]).await.unwrap().get(0);
+                if n == 0 {
+                    closed = true;
+                    break
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            assert!(closed);
+        })
+    }
+}
@@ -0,0 +1,288 @@
+//! Distributed locks built on Postgres advisory locks, keyed by strings hashed with hashit.
+//! A session lock is held by a SessionLock guard, which owns the pooled connection the lock was taken on
+//! and releases it when dropped. A transaction lock is released when its transaction ends,
+//! so the transaction itself acts as the guard.
+//! LeaderElection keeps a lock on a dedicated connection so that exactly one replica leads at a time
+
+use std::time::Duration;
+use tokio::{sync::watch, task::JoinHandle};
+use tokio_postgres::NoTls;
+use crate::core::GenericError;
+use crate::hashit::hash_string;
+use super::{is_sqlstate, Client, ConnPool, SimpleConfig, SqlState, Transaction};
+
+
+/// The advisory lock key for a name
+pub fn lock_key(name: &str) -> i64 {
+    hash_string(name) as i64
+}
+
+
+/// Holds a session advisory lock until it is dropped or unlocked
+pub struct SessionLock {
+    client: Option<Client>,
+    key: i64,
+}
+
+impl SessionLock {
+
+    /// The advisory lock key being held
+    pub fn key(&self) -> i64 {
+        self.key
+    }
+
+    /// Release the lock now, returning the connection to the pool
+    pub async fn unlock(mut self) -> Result<(), GenericError> {
+        if let Some(client) = self.client.take() {
+            client.execute("SELECT pg_advisory_unlock($1)", &[&self.key]).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => return,
+        };
+        let key = self.key;
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = client.execute("SELECT pg_advisory_unlock($1)", &[&key]).await {
+                        println!("ERROR! could not release advisory lock {}: {}", key, e);
+                    }
+                });
+            },
+            // without a runtime to unlock with, close the connection instead, which releases its locks
+            Err(_) => drop(client.into_inner()),
+        }
+    }
+}
+
+
+/// Closes the connection it holds when dropped, unless it is taken back first.
+/// This keeps a connection whose session is in an unknown state, i.e. with lock_timeout still set
+/// or a lock request still waiting on the server, from going back to the pool
+struct CloseOnDrop(Option<Client>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        if let Some(client) = self.0.take() {
+            drop(client.into_inner());
+        }
+    }
+}
+
+/// Wait until the named session lock is acquired. If this is cancelled while waiting, the connection is closed
+/// rather than pooled, since the server could still grant it the lock
+pub async fn lock(pool: &ConnPool, name: &str) -> Result<SessionLock, GenericError> {
+    let mut guard = CloseOnDrop(Some(pool.get().await?));
+    let key = lock_key(name);
+    guard.0.as_ref().unwrap().execute("SELECT pg_advisory_lock($1)", &[&key]).await?;
+    Ok(SessionLock{client: guard.0.take(), key})
+}
+
+/// Acquire the named session lock if it is free right now
+pub async fn try_lock(pool: &ConnPool, name: &str) -> Result<Option<SessionLock>, GenericError> {
+    let client = pool.get().await?;
+    let key = lock_key(name);
+    let acquired: bool = client.query_one("SELECT pg_try_advisory_lock($1)", &[&key]).await?.get(0);
+    match acquired {
+        true => Ok(Some(SessionLock{client: Some(client), key})),
+        false => Ok(None),
+    }
+}
+
+/// Wait up to timeout for the named session lock, returning None if it couldn't be acquired in time.
+/// If this is cancelled or fails before lock_timeout is reset, the connection is closed rather than pooled
+pub async fn lock_timeout(pool: &ConnPool, name: &str, timeout: Duration) -> Result<Option<SessionLock>, GenericError> {
+    let mut guard = CloseOnDrop(Some(pool.get().await?));
+    let key = lock_key(name);
+    let client = guard.0.as_ref().unwrap();
+    client.batch_execute(&format!("SET lock_timeout = {}", timeout.as_millis().max(1))).await?;
+    let result = client.execute("SELECT pg_advisory_lock($1)", &[&key]).await;
+    client.batch_execute("RESET lock_timeout").await?;
+    let client = guard.0.take();
+    match result {
+        Ok(_) => Ok(Some(SessionLock{client, key})),
+        Err(e) if is_sqlstate(&e, &SqlState::LOCK_NOT_AVAILABLE) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+/// Wait until the named lock is acquired for the rest of the transaction
+pub async fn lock_xact(tx: &Transaction<'_>, name: &str) -> Result<(), GenericError> {
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&lock_key(name)]).await?;
+    Ok(())
+}
+
+/// Acquire the named lock for the rest of the transaction if it is free right now, returning whether it was acquired
+pub async fn try_lock_xact(tx: &Transaction<'_>, name: &str) -> Result<bool, GenericError> {
+    let acquired: bool = tx.query_one("SELECT pg_try_advisory_xact_lock($1)", &[&lock_key(name)]).await?.get(0);
+    Ok(acquired)
+}
+
+
+/// Campaigns for leadership in the background. Every replica starts a LeaderElection with the same name,
+/// and whichever holds the lock leads until its connection is lost or it resigns.
+/// The connection is checked every check_interval, so a lost connection is noticed within that time,
+/// though the lock itself is released by Postgres as soon as the connection drops
+pub struct LeaderElection {
+    state: watch::Receiver<bool>,
+    task: JoinHandle<()>,
+}
+
+impl LeaderElection {
+
+    /// Start campaigning for the named leadership on a dedicated connection
+    pub fn start(config: &SimpleConfig, name: &str, check_interval: Duration) -> Self {
+        let pg_config = config.pg_config();
+        let key = lock_key(name);
+        let (sender, state) = watch::channel(false);
+        let task = tokio::spawn(async move {
+            loop {
+                let (client, connection) = match pg_config.connect(NoTls).await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("ERROR! leader election could not connect: {}", e);
+                        tokio::time::sleep(check_interval).await;
+                        continue
+                    }
+                };
+                let connection = tokio::spawn(connection);
+                loop {
+                    // once leading, re-taking the lock would stack it, so just check the connection is alive
+                    let was_leading = *sender.borrow();
+                    let result = match was_leading {
+                        true => client.query_one("SELECT true", &[]).await,
+                        false => client.query_one("SELECT pg_try_advisory_lock($1)", &[&key]).await,
+                    };
+                    let leading = match result {
+                        Ok(row) => row.get::<_, bool>(0),
+                        Err(_) => break,
+                    };
+                    sender.send_if_modified(|v| std::mem::replace(v, leading) != leading);
+                    tokio::time::sleep(check_interval).await;
+                }
+                sender.send_if_modified(|v| std::mem::replace(v, false));
+                connection.abort();
+                tokio::time::sleep(check_interval).await;
+            }
+        });
+        LeaderElection{state, task}
+    }
+
+    /// True if this replica currently leads
+    pub fn is_leader(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// Wait until this replica becomes the leader
+    pub async fn wait_for_leadership(&mut self) -> Result<(), GenericError> {
+        while !*self.state.borrow_and_update() {
+            self.state.changed().await?;
+        }
+        Ok(())
+    }
+
+    /// A receiver that is notified whenever leadership is gained or lost
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.state.clone()
+    }
+
+    /// Stop campaigning, giving up leadership if it is held
+    pub fn resign(self) {
+        // dropping self aborts the task, which closes the connection and releases the lock
+    }
+}
+
+impl Drop for LeaderElection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::{pool_no_tls_from_config, pool_no_tls_from_env, transaction, IsolationLevel};
+
+    #[test]
+    fn session_locks() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let held = lock(&pool, "_nexum_test_session_lock").await.unwrap();
+            assert!(try_lock(&pool, "_nexum_test_session_lock").await.unwrap().is_none());
+            assert!(lock_timeout(&pool, "_nexum_test_session_lock", Duration::from_millis(100)).await.unwrap().is_none());
+            // dropping the guard releases the lock in the background
+            drop(held);
+            let again = lock_timeout(&pool, "_nexum_test_session_lock", Duration::from_secs(5)).await.unwrap();
+            assert!(again.is_some());
+            again.unwrap().unlock().await.unwrap();
+            assert!(try_lock(&pool, "_nexum_test_session_lock").await.unwrap().is_some());
+
+            // giving up on lock_timeout doesn't leave a pooled connection with the timeout set
+            let held = lock(&pool, "_nexum_test_session_lock").await.unwrap();
+            let waiting = lock_timeout(&pool, "_nexum_test_session_lock", Duration::from_secs(5));
+            assert!(tokio::time::timeout(Duration::from_millis(100), waiting).await.is_err());
+            held.unlock().await.unwrap();
+            let client = pool.get().await.unwrap();
+            let setting: String = client.query_one("SHOW lock_timeout", &[]).await.unwrap().get(0);
+            assert_eq!(setting, "0");
+            drop(client);
+            // and the request that was still waiting can't take the lock on a pooled connection later
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(try_lock(&pool, "_nexum_test_session_lock").await.unwrap().is_some());
+        })
+    }
+
+    #[test]
+    fn transaction_locks() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let pool2 = pool.clone();
+            transaction(&pool, IsolationLevel::ReadCommitted, |tx| {
+                let pool2 = pool2.clone();
+                Box::pin(async move {
+                    assert!(try_lock_xact(tx, "_nexum_test_xact_lock").await?);
+                    assert!(try_lock(&pool2, "_nexum_test_xact_lock").await?.is_none());
+                    Ok(())
+                })
+            }).await.unwrap();
+            // the lock ended with the transaction
+            assert!(try_lock(&pool, "_nexum_test_xact_lock").await.unwrap().is_some());
+        })
+    }
+
+    #[test]
+    fn leader_election() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = SimpleConfig::new_from_env();
+            let interval = Duration::from_millis(50);
+            let mut first = LeaderElection::start(&config, "_nexum_test_leader", interval);
+            first.wait_for_leadership().await.unwrap();
+            let mut second = LeaderElection::start(&config, "_nexum_test_leader", interval);
+            tokio::time::sleep(interval * 4).await;
+            assert!(first.is_leader());
+            assert!(!second.is_leader());
+            // killing the leader's connection hands leadership over
+            let mut lost = first.subscribe();
+            let pool = pool_no_tls_from_config(&config).await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.execute("SELECT pg_terminate_backend(pid) FROM pg_locks WHERE locktype = 'advisory' AND objid = ($1::bigint & x'FFFFFFFF'::bigint)::oid", &[&lock_key("_nexum_test_leader")]).await.unwrap();
+            lost.changed().await.unwrap();
+            assert!(!*lost.borrow());
+            first.resign();
+            tokio::time::timeout(Duration::from_secs(5), second.wait_for_leadership()).await.unwrap().unwrap();
+            second.resign();
+        })
+    }
+}
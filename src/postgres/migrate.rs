@@ -0,0 +1,321 @@
+//! A schema migration runner. Migrations are ordered by version and either loaded from a directory
+//! or embedded in the binary with include_str!, i.e.
+//! ```ignore
+//! let migrations = vec![
+//!     Migration::new(1, "create_users", include_str!("../migrations/0001_create_users.up.sql"), Some(include_str!("../migrations/0001_create_users.down.sql"))),
+//! ];
+//! migrate::up(&pool, &migrations, false).await?;
+//! ```
+//! Applied migrations are recorded in the _nexum_migrations table along with a checksum of their SQL,
+//! so editing a migration after it has been applied is caught rather than silently ignored.
+//! A session advisory lock is held while migrating, so replicas that deploy at the same time take turns
+
+use std::{collections::{HashMap, HashSet}, error::Error, fmt, fs, path::Path};
+use crate::core::GenericError;
+use crate::hashit::hash_string;
+use super::{Client, ConnPool};
+
+/// The table where applied migrations are recorded
+pub const MIGRATIONS_TABLE: &str = "_nexum_migrations";
+
+
+/// A single schema change, with an optional way to undo it
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: i64,
+    pub name: String,
+    pub up: String,
+    pub down: Option<String>,
+}
+
+impl Migration {
+
+    /// Create a migration, typically from SQL embedded with include_str!
+    pub fn new(version: i64, name: &str, up: &str, down: Option<&str>) -> Self {
+        Migration {
+            version,
+            name: name.to_string(),
+            up: up.to_string(),
+            down: down.map(|s| s.to_string()),
+        }
+    }
+
+    /// The checksum of the up SQL, as stored in the migrations table
+    pub fn checksum(&self) -> i64 {
+        hash_string(&self.up) as i64
+    }
+}
+
+
+/// Load migrations from a directory of files named like 0001_create_users.up.sql and 0001_create_users.down.sql
+/// (a plain 0001_create_users.sql is treated as an up migration). The result is sorted by version
+pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<Migration>, GenericError> {
+    let mut by_version: HashMap<i64, Migration> = HashMap::new();
+    let mut with_up = HashSet::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = match path.file_name().and_then(|f| f.to_str()) {
+            Some(f) if f.ends_with(".sql") => f.trim_end_matches(".sql").to_string(),
+            _ => continue,
+        };
+        let (stem, is_down) = match file_name.strip_suffix(".down") {
+            Some(stem) => (stem.to_string(), true),
+            None => (file_name.trim_end_matches(".up").to_string(), false),
+        };
+        let (version, name) = match stem.split_once('_') {
+            Some((version, name)) => (version, name),
+            None => (stem.as_str(), ""),
+        };
+        let version: i64 = version.parse().map_err(|_| MigrationError{message: format!("{} does not start with a version number", path.display())})?;
+        let sql = fs::read_to_string(&path)?;
+        let migration = by_version.entry(version).or_insert_with(|| Migration::new(version, name, "", None));
+        // read_dir order is arbitrary, so two files for the same version must agree rather than the last one winning
+        if migration.name != name {
+            return Err(MigrationError{message: format!("migration {} is named both {} and {}", version, migration.name, name)}.into())
+        }
+        let duplicate = match is_down {
+            true => migration.down.replace(sql).is_some(),
+            false => {
+                migration.up = sql;
+                !with_up.insert(version)
+            },
+        };
+        if duplicate {
+            let kind = if is_down { "down" } else { "up" };
+            return Err(MigrationError{message: format!("migration {}_{} has more than one {} file", version, name, kind)}.into())
+        }
+    }
+    if let Some(m) = by_version.values().find(|m| !with_up.contains(&m.version)) {
+        return Err(MigrationError{message: format!("migration {}_{} has a down file but no up file", m.version, m.name)}.into())
+    }
+    let mut migrations: Vec<Migration> = by_version.into_values().collect();
+    migrations.sort_by_key(|m| m.version);
+    Ok(migrations)
+}
+
+
+/// Whether a known migration has been applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub applied: bool,
+}
+
+/// A row of the migrations table
+struct Applied {
+    version: i64,
+    name: String,
+    checksum: i64,
+}
+
+/// read the migrations table, or return nothing if it doesn't exist yet
+async fn applied(client: &Client) -> Result<Vec<Applied>, GenericError> {
+    let exists: bool = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&MIGRATIONS_TABLE]).await?.get(0);
+    if !exists {
+        return Ok(Vec::new())
+    }
+    let rows = client.query(format!("SELECT version, name, checksum FROM {} ORDER BY version", MIGRATIONS_TABLE).as_str(), &[]).await?;
+    Ok(rows.iter().map(|row| Applied{version: row.get(0), name: row.get(1), checksum: row.get(2)}).collect())
+}
+
+/// ensure every applied migration still has the same SQL it was applied with
+fn verify_checksums(migrations: &[Migration], applied: &[Applied]) -> Result<(), GenericError> {
+    for a in applied {
+        if let Some(m) = migrations.iter().find(|m| m.version == a.version) {
+            if m.checksum() != a.checksum {
+                return Err(MigrationError{message: format!("migration {} ({}) has changed since it was applied", a.version, a.name)}.into())
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the key of the advisory lock held while migrating
+fn lock_key() -> i64 {
+    hash_string(MIGRATIONS_TABLE) as i64
+}
+
+
+/// Report which of the migrations have been applied
+pub async fn status(pool: &ConnPool, migrations: &[Migration]) -> Result<Vec<MigrationStatus>, GenericError> {
+    let client = pool.get().await?;
+    let applied = applied(&client).await?;
+    verify_checksums(migrations, &applied)?;
+    let statuses = migrations.iter().map(|m| MigrationStatus{
+        version: m.version,
+        name: m.name.clone(),
+        applied: applied.iter().any(|a| a.version == m.version),
+    }).collect();
+    Ok(statuses)
+}
+
+/// Apply every migration that hasn't been applied yet, in version order, each in its own transaction.
+/// Returns the migrations that were applied, or that would have been if dry_run is true
+pub async fn up<'m>(pool: &ConnPool, migrations: &'m [Migration], dry_run: bool) -> Result<Vec<&'m Migration>, GenericError> {
+    let mut client = pool.get().await?;
+    client.execute("SELECT pg_advisory_lock($1)", &[&lock_key()]).await?;
+    let result = apply_up(&mut client, migrations, dry_run).await;
+    // release the lock before returning, so a failed migration's error isn't replaced by the unlock's
+    let unlocked = client.execute("SELECT pg_advisory_unlock($1)", &[&lock_key()]).await;
+    let migrated = result?;
+    unlocked?;
+    Ok(migrated)
+}
+
+async fn apply_up<'m>(client: &mut Client, migrations: &'m [Migration], dry_run: bool) -> Result<Vec<&'m Migration>, GenericError> {
+    let applied = applied(client).await?;
+    verify_checksums(migrations, &applied)?;
+    let mut pending: Vec<&Migration> = migrations.iter().filter(|m| !applied.iter().any(|a| a.version == m.version)).collect();
+    pending.sort_by_key(|m| m.version);
+    if dry_run {
+        return Ok(pending)
+    }
+    client.batch_execute(&format!("CREATE TABLE IF NOT EXISTS {} (
+        version BIGINT PRIMARY KEY,
+        name TEXT NOT NULL,
+        checksum BIGINT NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )", MIGRATIONS_TABLE)).await?;
+    let insert = format!("INSERT INTO {} (version, name, checksum) VALUES ($1, $2, $3)", MIGRATIONS_TABLE);
+    for m in &pending {
+        let tx = client.transaction().await?;
+        tx.batch_execute(&m.up).await?;
+        tx.execute(insert.as_str(), &[&m.version, &m.name, &m.checksum()]).await?;
+        tx.commit().await?;
+    }
+    Ok(pending)
+}
+
+/// Revert every applied migration with a version greater than target, newest first, each in its own transaction.
+/// Returns the migrations that were reverted, or that would have been if dry_run is true
+pub async fn down<'m>(pool: &ConnPool, migrations: &'m [Migration], target: i64, dry_run: bool) -> Result<Vec<&'m Migration>, GenericError> {
+    let mut client = pool.get().await?;
+    client.execute("SELECT pg_advisory_lock($1)", &[&lock_key()]).await?;
+    let result = apply_down(&mut client, migrations, target, dry_run).await;
+    // release the lock before returning, so a failed migration's error isn't replaced by the unlock's
+    let unlocked = client.execute("SELECT pg_advisory_unlock($1)", &[&lock_key()]).await;
+    let migrated = result?;
+    unlocked?;
+    Ok(migrated)
+}
+
+async fn apply_down<'m>(client: &mut Client, migrations: &'m [Migration], target: i64, dry_run: bool) -> Result<Vec<&'m Migration>, GenericError> {
+    let applied = applied(client).await?;
+    verify_checksums(migrations, &applied)?;
+    let mut reverting = Vec::new();
+    for a in applied.iter().rev().filter(|a| a.version > target) {
+        let m = migrations.iter().find(|m| m.version == a.version)
+            .ok_or_else(|| MigrationError{message: format!("migration {} ({}) was applied but is not known", a.version, a.name)})?;
+        if m.down.is_none() {
+            return Err(MigrationError{message: format!("migration {} ({}) has no down migration", m.version, m.name)}.into())
+        }
+        reverting.push(m);
+    }
+    if dry_run {
+        return Ok(reverting)
+    }
+    let delete = format!("DELETE FROM {} WHERE version = $1", MIGRATIONS_TABLE);
+    for m in &reverting {
+        let tx = client.transaction().await?;
+        tx.batch_execute(m.down.as_deref().unwrap_or_default()).await?;
+        tx.execute(delete.as_str(), &[&m.version]).await?;
+        tx.commit().await?;
+    }
+    Ok(reverting)
+}
+
+
+/// Use this struct when migrations can't be loaded or don't match what was applied
+#[derive(Debug)]
+pub struct MigrationError {
+    pub message: String,
+}
+
+impl Error for MigrationError {}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MigrationError: {}", self.message)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::pool_no_tls_from_env;
+
+    #[test]
+    fn load_from_dir() {
+        let dir = std::env::temp_dir().join(format!("nexum_migrations_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0002_add_email.up.sql"), "ALTER TABLE people ADD COLUMN email TEXT").unwrap();
+        fs::write(dir.join("0002_add_email.down.sql"), "ALTER TABLE people DROP COLUMN email").unwrap();
+        fs::write(dir.join("0001_create_people.sql"), "CREATE TABLE people (id INT)").unwrap();
+        fs::write(dir.join("README.md"), "not a migration").unwrap();
+        let migrations = from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!((migrations[0].version, migrations[0].name.as_str()), (1, "create_people"));
+        assert!(migrations[0].down.is_none());
+        assert_eq!((migrations[1].version, migrations[1].name.as_str()), (2, "add_email"));
+        assert_eq!(migrations[1].down.as_deref(), Some("ALTER TABLE people DROP COLUMN email"));
+
+        // a down file on its own is a mistake, not a migration that does nothing
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0003_orphan.down.sql"), "DROP TABLE people").unwrap();
+        let orphaned = from_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(orphaned.is_err());
+
+        // two files for the same version are an error rather than one quietly replacing the other
+        for files in [["0005_add_a.sql", "0005_add_b.sql"], ["0005_x.sql", "0005_x.up.sql"], ["0005_x.up.sql", "0005_y.down.sql"]] {
+            fs::create_dir_all(&dir).unwrap();
+            for file in files {
+                fs::write(dir.join(file), "SELECT 1").unwrap();
+            }
+            let clashing = from_dir(&dir);
+            fs::remove_dir_all(&dir).unwrap();
+            assert!(clashing.unwrap_err().downcast_ref::<MigrationError>().is_some(), "{:?}", files);
+        }
+    }
+
+    #[test]
+    fn migrate_up_and_down() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_migrations, _nexum_test_mig").await.unwrap();
+            let mut migrations = vec![
+                Migration::new(1, "create", "CREATE TABLE _nexum_test_mig (id INT)", Some("DROP TABLE _nexum_test_mig")),
+                Migration::new(2, "add_name", "ALTER TABLE _nexum_test_mig ADD COLUMN name TEXT", Some("ALTER TABLE _nexum_test_mig DROP COLUMN name")),
+            ];
+            // a dry run changes nothing
+            assert_eq!(up(&pool, &migrations, true).await.unwrap().len(), 2);
+            assert!(status(&pool, &migrations).await.unwrap().iter().all(|s| !s.applied));
+            // applying twice only applies once
+            assert_eq!(up(&pool, &migrations, false).await.unwrap().len(), 2);
+            assert_eq!(up(&pool, &migrations, false).await.unwrap().len(), 0);
+            client.execute("INSERT INTO _nexum_test_mig (id, name) VALUES (1, 'x')", &[]).await.unwrap();
+            // revert the newest migration only
+            let reverted = down(&pool, &migrations, 1, false).await.unwrap();
+            assert_eq!(reverted.iter().map(|m| m.version).collect::<Vec<i64>>(), vec![2]);
+            let statuses = status(&pool, &migrations).await.unwrap();
+            assert_eq!(statuses.iter().map(|s| s.applied).collect::<Vec<bool>>(), vec![true, false]);
+            // editing an applied migration is an error
+            migrations[0].up.push_str(" -- edited");
+            assert!(up(&pool, &migrations, false).await.is_err());
+            // a failed migration reports its own error and releases the lock
+            migrations[0].up = "CREATE TABLE _nexum_test_mig (id INT)".to_string();
+            migrations.push(Migration::new(3, "broken", "ALTER TABLE _nexum_test_mig ADD COLUMN", None));
+            let e = up(&pool, &migrations, false).await.unwrap_err();
+            let code = e.downcast_ref::<tokio_postgres::Error>().and_then(|e| e.code());
+            assert_eq!(code, Some(&tokio_postgres::error::SqlState::SYNTAX_ERROR));
+            // migration 2 was applied before 3 failed
+            assert_eq!(down(&pool, &migrations, 0, false).await.unwrap().len(), 2);
+        })
+    }
+}
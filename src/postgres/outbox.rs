@@ -0,0 +1,447 @@
+//! A transactional outbox. Instead of pushing to SQS or upserting to OpenSearch after a commit, which loses the update
+//! if the push fails, an event is written to the _nexum_outbox table in the same transaction as the change it describes:
+//! ```ignore
+//! transaction(&pool, IsolationLevel::ReadCommitted, |tx| Box::pin(async move {
+//!     tx.execute("UPDATE users SET name = $1 WHERE id = $2", &[&name, &id]).await?;
+//!     outbox::write(tx, &Event::opensearch("users", &id.to_string(), &user)?).await?;
+//!     Ok(())
+//! })).await?;
+//! ```
+//! A Relay then delivers events to wherever they are destined, retrying with backoff until it succeeds or the event
+//! runs out of attempts and is dead-lettered. Relays claim events with FOR UPDATE SKIP LOCKED, so several can run at once,
+//! and events with the same destination and topic (an SQS message group, a Redis key or an OpenSearch index)
+//! are delivered in the order they were written.
+//! Delivery is at least once: if a relay dies after dispatching but before recording it, the event is dispatched again.
+//! SQS uses the idempotency key as the deduplication id, and Redis SETs and OpenSearch upserts are idempotent already
+
+use std::{collections::HashMap, time::Duration};
+use async_trait::async_trait;
+use mobc_redis::redis::AsyncCommands;
+use serde::Serialize;
+use serde_json::{self, Value};
+use tokio::task::JoinHandle;
+use crate::core::{GenericError, SimpleError};
+use crate::hashit::hash_string;
+use crate::{opensearch, redis::RedisPool, sqs::Messenger};
+use super::{Client, ConnPool, Row, Transaction};
+
+/// The table events are written to
+pub const OUTBOX_TABLE: &str = "_nexum_outbox";
+/// The destination name Event::sqs uses
+pub const SQS: &str = "sqs";
+/// The destination name Event::redis uses
+pub const REDIS: &str = "redis";
+/// The destination name Event::opensearch uses
+pub const OPENSEARCH: &str = "opensearch";
+const DEFAULT_BATCH_SIZE: i64 = 50;
+const DEFAULT_MAX_ATTEMPTS: i32 = 10;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 500;
+
+
+/// An event to write to the outbox
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub destination: String,
+    pub topic: String,
+    pub key: Option<String>,
+    pub payload: String,
+    pub idempotency_key: Option<String>,
+}
+
+impl Event {
+
+    /// An event for the dispatcher registered under destination. What topic and key mean is up to the dispatcher
+    pub fn new<T: Serialize>(destination: &str, topic: &str, key: Option<&str>, payload: &T) -> Result<Self, GenericError> {
+        Ok(Event {
+            destination: destination.to_string(),
+            topic: topic.to_string(),
+            key: key.map(|k| k.to_string()),
+            payload: serde_json::to_string(payload)?,
+            idempotency_key: None,
+        })
+    }
+
+    /// A message pushed to SQS with a message group id
+    pub fn sqs<T: Serialize>(group_id: &str, msg: &T) -> Result<Self, GenericError> {
+        Event::new(SQS, group_id, None, msg)
+    }
+
+    /// A value SET at a Redis key
+    pub fn redis<T: Serialize>(key: &str, value: &T) -> Result<Self, GenericError> {
+        Event::new(REDIS, key, None, value)
+    }
+
+    /// A document upserted into an OpenSearch index
+    pub fn opensearch<T: Serialize>(index: &str, id: &str, doc: &T) -> Result<Self, GenericError> {
+        Event::new(OPENSEARCH, index, Some(id), doc)
+    }
+
+    /// Writing another event with the same idempotency key does nothing, so a retried request doesn't send twice.
+    /// Without one a random key is generated
+    pub fn idempotency_key(mut self, key: &str) -> Self {
+        self.idempotency_key = Some(key.to_string());
+        self
+    }
+}
+
+
+/// An event read back from the outbox to be dispatched
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub id: i64,
+    pub destination: String,
+    pub topic: String,
+    pub key: Option<String>,
+    pub payload: String,
+    pub idempotency_key: String,
+    /// how many times dispatching has been tried, including this time
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
+impl Record {
+    fn from_row(row: &Row) -> Self {
+        Record {
+            id: row.get("id"),
+            destination: row.get("destination"),
+            topic: row.get("topic"),
+            key: row.get("key"),
+            payload: row.get("payload"),
+            idempotency_key: row.get("idempotency_key"),
+            attempts: row.get("attempts"),
+            last_error: row.get("last_error"),
+        }
+    }
+}
+
+
+/// Write an event as part of a transaction, returning false if an event with the same idempotency key was already written.
+/// The outbox table must exist, which Relay::new and create_table ensure
+pub async fn write(tx: &Transaction<'_>, event: &Event) -> Result<bool, GenericError> {
+    let query = format!("INSERT INTO {} (destination, topic, key, payload, idempotency_key)
+        VALUES ($1, $2, $3, $4, COALESCE($5, gen_random_uuid()::text)) ON CONFLICT (idempotency_key) DO NOTHING", OUTBOX_TABLE);
+    let n = tx.execute(query.as_str(), &[&event.destination, &event.topic, &event.key, &event.payload, &event.idempotency_key]).await?;
+    Ok(n == 1)
+}
+
+/// create the outbox table and its index if they don't exist
+/// CREATE TABLE IF NOT EXISTS can still fail when two sessions run it at once, so take a lock first
+pub async fn create_table(client: &Client) -> Result<(), GenericError> {
+    client.batch_execute(&format!("
+        BEGIN;
+        SELECT pg_advisory_xact_lock({lock});
+        CREATE TABLE IF NOT EXISTS {t} (
+            id BIGSERIAL PRIMARY KEY,
+            destination TEXT NOT NULL,
+            topic TEXT NOT NULL,
+            key TEXT,
+            payload TEXT NOT NULL,
+            idempotency_key TEXT NOT NULL UNIQUE,
+            attempts INT NOT NULL DEFAULT 0,
+            available_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            dispatched_at TIMESTAMPTZ,
+            dead BOOLEAN NOT NULL DEFAULT false,
+            last_error TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE INDEX IF NOT EXISTS {t}_pending ON {t} (destination, topic, id) WHERE dispatched_at IS NULL AND NOT dead;
+        COMMIT;
+    ", t = OUTBOX_TABLE, lock = hash_string(OUTBOX_TABLE) as i64)).await?;
+    Ok(())
+}
+
+
+/// Delivers outbox records to one destination. Returning an error means the record is retried later
+#[async_trait]
+pub trait Dispatch: Send + Sync {
+    async fn dispatch(&self, record: &Record) -> Result<(), GenericError>;
+}
+
+/// Pushes the payload with the topic as the message group id and the idempotency key as the deduplication id
+#[async_trait]
+impl Dispatch for Messenger {
+    async fn dispatch(&self, record: &Record) -> Result<(), GenericError> {
+        self.push_str(&record.payload, &record.topic, Some(&record.idempotency_key)).await?;
+        Ok(())
+    }
+}
+
+/// SETs the payload at the topic
+#[async_trait]
+impl Dispatch for RedisPool {
+    async fn dispatch(&self, record: &Record) -> Result<(), GenericError> {
+        let mut rconn = self.get().await?;
+        let _ : () = rconn.set(&record.topic, &record.payload).await?;
+        Ok(())
+    }
+}
+
+/// Upserts the payload into the index named by the topic, with the key as the document id
+pub struct OpenSearchDispatch;
+
+#[async_trait]
+impl Dispatch for OpenSearchDispatch {
+    async fn dispatch(&self, record: &Record) -> Result<(), GenericError> {
+        let id = record.key.as_deref().ok_or_else(|| SimpleError::from_str("an OpenSearch event needs a key to use as the document id"))?;
+        let doc: Value = serde_json::from_str(&record.payload)?;
+        opensearch::upsert_doc(&record.topic, id, &doc).await?;
+        Ok(())
+    }
+}
+
+
+/// Delivers outbox events to the destinations registered with it
+pub struct Relay {
+    pool: ConnPool,
+    dispatchers: HashMap<String, Box<dyn Dispatch>>,
+    batch_size: i64,
+    max_attempts: i32,
+    retry_backoff: Duration,
+}
+
+impl Relay {
+
+    /// Instantiate a relay with no destinations, creating the outbox table if it doesn't exist yet
+    pub async fn new(pool: &ConnPool) -> Result<Self, GenericError> {
+        let client = pool.get().await?;
+        create_table(&client).await?;
+        Ok(Relay {
+            pool: pool.clone(),
+            dispatchers: HashMap::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS),
+        })
+    }
+
+    /// Deliver events for a destination with a dispatcher. Events for destinations without one are left for other relays
+    pub fn destination<D: Dispatch + 'static>(mut self, name: &str, dispatcher: D) -> Self {
+        self.dispatchers.insert(name.to_string(), Box::new(dispatcher));
+        self
+    }
+
+    /// Deliver Event::sqs events with a Messenger
+    pub fn sqs(self, messenger: Messenger) -> Self {
+        self.destination(SQS, messenger)
+    }
+
+    /// Deliver Event::redis events with a RedisPool
+    pub fn redis(self, pool: RedisPool) -> Self {
+        self.destination(REDIS, pool)
+    }
+
+    /// Deliver Event::opensearch events
+    pub fn opensearch(self) -> Self {
+        self.destination(OPENSEARCH, OpenSearchDispatch)
+    }
+
+    /// The most events a single relay_once claims
+    pub fn batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How many times an event is tried before it is dead-lettered
+    pub fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// How long to wait before the first retry, doubling with each subsequent attempt
+    pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    fn destinations(&self) -> Vec<String> {
+        self.dispatchers.keys().cloned().collect()
+    }
+
+    /// Claim and dispatch one batch of events, returning how many were dispatched.
+    /// Events wait behind an earlier event with the same destination and topic that is waiting to be retried,
+    /// and a relay takes a transaction lock on each topic it claims so two relays never interleave one topic.
+    /// The claim is held until the batch is done
+    pub async fn relay_once(&self) -> Result<usize, GenericError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let claim = format!("SELECT id, destination, topic, key, payload, idempotency_key, attempts + 1 AS attempts, last_error FROM {t} o
+            WHERE destination = ANY($1) AND dispatched_at IS NULL AND NOT dead AND available_at <= now()
+            AND NOT EXISTS (
+                SELECT 1 FROM {t} e WHERE e.destination = o.destination AND e.topic = o.topic AND e.id < o.id
+                AND e.dispatched_at IS NULL AND NOT e.dead AND e.available_at > now()
+            )
+            AND pg_try_advisory_xact_lock(hashtextextended('{t}:' || destination || ':' || topic, 0))
+            ORDER BY id LIMIT $2 FOR UPDATE SKIP LOCKED", t = OUTBOX_TABLE);
+        let rows = tx.query(claim.as_str(), &[&self.destinations(), &self.batch_size]).await?;
+        let records: Vec<Record> = rows.iter().map(Record::from_row).collect();
+        let mut dispatched = 0;
+        let mut blocked: Vec<(&str, &str)> = Vec::new();
+        for record in &records {
+            // once an event fails, later events on the same topic wait for it
+            if blocked.contains(&(record.destination.as_str(), record.topic.as_str())) {
+                continue
+            }
+            let result = match self.dispatchers.get(&record.destination) {
+                Some(dispatcher) => dispatcher.dispatch(record).await,
+                None => Err(SimpleError::from_str("no dispatcher for this destination").into()),
+            };
+            match result {
+                Ok(()) => {
+                    let query = format!("UPDATE {} SET dispatched_at = now(), attempts = $2 WHERE id = $1", OUTBOX_TABLE);
+                    tx.execute(query.as_str(), &[&record.id, &record.attempts]).await?;
+                    dispatched += 1;
+                },
+                Err(e) => {
+                    println!("ERROR! could not dispatch outbox event {} to {}: {}", record.id, record.destination, e);
+                    blocked.push((&record.destination, &record.topic));
+                    let backoff = self.retry_backoff.as_secs_f64() * 2f64.powi(record.attempts - 1);
+                    let query = format!("UPDATE {} SET attempts = $2, last_error = $3, dead = $2::int >= $4::int,
+                        available_at = now() + make_interval(secs => $5) WHERE id = $1", OUTBOX_TABLE);
+                    tx.execute(query.as_str(), &[&record.id, &record.attempts, &e.to_string(), &self.max_attempts, &backoff]).await?;
+                },
+            }
+        }
+        tx.commit().await?;
+        Ok(dispatched)
+    }
+
+    /// Relay events in the background until the returned handle is aborted,
+    /// checking for new ones every poll_interval when the outbox is empty
+    pub fn spawn(self, poll_interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.relay_once().await {
+                    Ok(n) if n as i64 >= self.batch_size => continue,
+                    Ok(_) => (),
+                    Err(e) => println!("ERROR! outbox relay failed: {}", e),
+                }
+                tokio::time::sleep(poll_interval).await;
+            }
+        })
+    }
+
+    /// List the events for this relay's destinations that have been dead-lettered
+    pub async fn dead_letters(&self) -> Result<Vec<Record>, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("SELECT id, destination, topic, key, payload, idempotency_key, attempts, last_error FROM {}
+            WHERE destination = ANY($1) AND dead ORDER BY id", OUTBOX_TABLE);
+        let rows = client.query(query.as_str(), &[&self.destinations()]).await?;
+        Ok(rows.iter().map(Record::from_row).collect())
+    }
+
+    /// Put a dead-lettered event back in the outbox with its attempts reset
+    pub async fn retry_dead(&self, id: i64) -> Result<(), GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("UPDATE {} SET dead = false, attempts = 0, available_at = now() WHERE id = $1 AND dead", OUTBOX_TABLE);
+        client.execute(query.as_str(), &[&id]).await?;
+        Ok(())
+    }
+
+    /// How many events for this relay's destinations are waiting to be dispatched, not counting dead letters
+    pub async fn pending(&self) -> Result<i64, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("SELECT COUNT(*) FROM {} WHERE destination = ANY($1) AND dispatched_at IS NULL AND NOT dead", OUTBOX_TABLE);
+        Ok(client.query_one(query.as_str(), &[&self.destinations()]).await?.get(0))
+    }
+
+    /// Delete dispatched events older than max_age, returning how many were deleted
+    pub async fn purge(&self, max_age: Duration) -> Result<u64, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("DELETE FROM {} WHERE destination = ANY($1) AND dispatched_at < now() - make_interval(secs => $2)", OUTBOX_TABLE);
+        Ok(client.execute(query.as_str(), &[&self.destinations(), &max_age.as_secs_f64()]).await?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::runtime::Runtime;
+    use crate::postgres::{pool_no_tls_from_env, transaction, IsolationLevel};
+
+    /// Records what it dispatched, failing whenever the payload is in fail_on
+    #[derive(Clone, Default)]
+    struct Recorder {
+        sent: Arc<Mutex<Vec<String>>>,
+        fail_on: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Dispatch for Recorder {
+        async fn dispatch(&self, record: &Record) -> Result<(), GenericError> {
+            if self.fail_on.lock().unwrap().contains(&record.payload) {
+                return Err(SimpleError::from_str("unavailable").into())
+            }
+            self.sent.lock().unwrap().push(format!("{}:{}", record.topic, record.payload));
+            Ok(())
+        }
+    }
+
+    async fn write_events(pool: &ConnPool, destination: &str, events: Vec<(&str, i32, Option<&str>)>, fail: bool) -> Result<(), GenericError> {
+        let destination = destination.to_string();
+        let events: Vec<(String, i32, Option<String>)> = events.into_iter().map(|(t, p, k)| (t.to_string(), p, k.map(|k| k.to_string()))).collect();
+        transaction(pool, IsolationLevel::ReadCommitted, |tx| {
+            let destination = destination.clone();
+            let events = events.clone();
+            Box::pin(async move {
+                for (topic, payload, key) in &events {
+                    let mut event = Event::new(&destination, topic, None, payload)?;
+                    if let Some(key) = key {
+                        event = event.idempotency_key(key);
+                    }
+                    write(tx, &event).await?;
+                }
+                match fail {
+                    true => Err(SimpleError::from_str("rolled back").into()),
+                    false => Ok(()),
+                }
+            })
+        }).await
+    }
+
+    #[test]
+    fn relay_in_order_with_retries() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let recorder = Recorder::default();
+            let relay = Relay::new(&pool).await.unwrap()
+                .destination("_nexum_test_outbox", recorder.clone())
+                .retry_backoff(Duration::from_millis(200))
+                .max_attempts(2);
+            let client = pool.get().await.unwrap();
+            client.execute(format!("DELETE FROM {} WHERE destination = '_nexum_test_outbox'", OUTBOX_TABLE).as_str(), &[]).await.unwrap();
+            // nothing is written if the transaction rolls back
+            assert!(write_events(&pool, "_nexum_test_outbox", vec![("a", 0, None)], true).await.is_err());
+            assert_eq!(relay.pending().await.unwrap(), 0);
+            // a repeated idempotency key is only written once
+            write_events(&pool, "_nexum_test_outbox", vec![("a", 1, Some("_nexum_test_k1")), ("a", 1, Some("_nexum_test_k1")),
+                ("a", 2, None), ("b", 3, None), ("a", 4, None)], false).await.unwrap();
+            assert_eq!(relay.pending().await.unwrap(), 4);
+            // while a:2 fails, a:4 waits behind it but b carries on
+            recorder.fail_on.lock().unwrap().push("2".to_string());
+            assert_eq!(relay.relay_once().await.unwrap(), 2);
+            assert_eq!(*recorder.sent.lock().unwrap(), vec!["a:1", "b:3"]);
+            assert_eq!(relay.relay_once().await.unwrap(), 0);
+            recorder.fail_on.lock().unwrap().clear();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(relay.relay_once().await.unwrap(), 2);
+            assert_eq!(*recorder.sent.lock().unwrap(), vec!["a:1", "b:3", "a:2", "a:4"]);
+            assert_eq!(relay.pending().await.unwrap(), 0);
+            // an event that keeps failing is dead-lettered, and can be retried
+            recorder.fail_on.lock().unwrap().push("5".to_string());
+            write_events(&pool, "_nexum_test_outbox", vec![("c", 5, None)], false).await.unwrap();
+            relay.relay_once().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            relay.relay_once().await.unwrap();
+            let dead = relay.dead_letters().await.unwrap();
+            assert_eq!(dead.len(), 1);
+            assert_eq!((dead[0].attempts, dead[0].last_error.as_deref()), (2, Some("SimpleError: unavailable")));
+            recorder.fail_on.lock().unwrap().clear();
+            relay.retry_dead(dead[0].id).await.unwrap();
+            assert_eq!(relay.relay_once().await.unwrap(), 1);
+            assert_eq!(relay.purge(Duration::ZERO).await.unwrap(), 5);
+        })
+    }
+}
@@ -0,0 +1,252 @@
+//! Keyset pagination with opaque cursors. Instead of OFFSET, which reads and throws away every skipped row,
+//! each page starts after the ordering column values of the last row of the previous page, i.e.
+//! ```ignore
+//! let query = QueryBuilder::new("SELECT id, name, created_at FROM users").where_eq("active", true);
+//! let page = paginate(&client, query, &["created_at", "id"], Order::Desc, cursor.as_deref(), 50, &|row| row.get::<_, i64>("id")).await?;
+//! ```
+//! A cursor is base64 encoded JSON of those values, so it can be handed to API clients and sent back as is.
+//! The ordering columns must be selected, must not be NULL and should end with something unique (like id),
+//! otherwise rows with equal values could be skipped. Cursors are in the same form as OpenSearch's
+//! search_after values, so opensearch::search_page returns the same Page
+
+use std::{error::Error, marker::Sync};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use crate::core::{GenericError, SimpleError};
+use super::{query::Param, Order, Queryable, QueryBuilder, Row};
+
+/// How timestamps without a time zone are written in cursors
+const NAIVE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+
+/// One page of results. next_cursor fetches the page after this one and prev_cursor the page before,
+/// each being None when there is nothing more in that direction
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+}
+
+
+/// The values of the ordering columns to continue from, and which way to go
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub values: Vec<Value>,
+    #[serde(default)]
+    pub backward: bool,
+}
+
+impl Cursor {
+
+    /// The opaque string handed out to clients
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// Decode a cursor string that was produced by encode()
+    pub fn decode(cursor: &str) -> Result<Self, GenericError> {
+        let invalid = |e: String| SimpleError{message: format!("\"{}\" is not a valid cursor: {}", cursor, e)};
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).map_err(|e| invalid(e.to_string()))?;
+        let cursor = serde_json::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?;
+        Ok(cursor)
+    }
+}
+
+
+/// Build a page from up to page_size + 1 results, fetched in the cursor's direction.
+/// values returns the ordering values of a result, which become the cursors
+pub fn page<R, T>(mut results: Vec<R>, page_size: usize, cursor: Option<&Cursor>, values: impl Fn(&R) -> Result<Vec<Value>, GenericError>, map: impl Fn(R) -> T) -> Result<Page<T>, GenericError> {
+    let more = results.len() > page_size;
+    results.truncate(page_size);
+    let backward = cursor.is_some_and(|c| c.backward);
+    if backward {
+        results.reverse();
+    }
+    let (next, prev) = match (results.first(), results.last()) {
+        (Some(first), Some(last)) => {
+            // going forward there is a previous page if we started from a cursor, and going backward there is always a next page
+            let has_next = if backward { true } else { more };
+            let has_prev = if backward { more } else { cursor.is_some() };
+            let next = match has_next {
+                true => Some(Cursor{values: values(last)?, backward: false}),
+                false => None,
+            };
+            let prev = match has_prev {
+                true => Some(Cursor{values: values(first)?, backward: true}),
+                false => None,
+            };
+            (next, prev)
+        },
+        // an empty page can still turn around at the cursor it came from
+        _ => match cursor {
+            Some(c) => {
+                let turned = Cursor{values: c.values.clone(), backward: !c.backward};
+                match c.backward {
+                    true => (Some(turned), None),
+                    false => (None, Some(turned)),
+                }
+            },
+            None => (None, None),
+        },
+    };
+    Ok(Page {
+        items: results.into_iter().map(map).collect(),
+        next_cursor: next.map(|c| c.encode()),
+        prev_cursor: prev.map(|c| c.encode()),
+    })
+}
+
+
+/// A cursor value bound as a query parameter, converted to whatever type the column it is compared with has
+#[derive(Debug)]
+struct CursorParam(Value);
+
+impl ToSql for CursorParam {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match (&self.0, ty) {
+            (Value::Number(n), &Type::INT2) => i16::try_from(as_i64(n)?)?.to_sql(ty, out),
+            (Value::Number(n), &Type::INT4) => i32::try_from(as_i64(n)?)?.to_sql(ty, out),
+            (Value::Number(n), &Type::INT8) => as_i64(n)?.to_sql(ty, out),
+            (Value::Number(n), &Type::FLOAT4) => (as_f64(n)? as f32).to_sql(ty, out),
+            (Value::Number(n), &Type::FLOAT8) => as_f64(n)?.to_sql(ty, out),
+            (Value::Bool(b), &Type::BOOL) => b.to_sql(ty, out),
+            (Value::String(s), &Type::TIMESTAMPTZ) => DateTime::parse_from_rfc3339(s)?.with_timezone(&Utc).to_sql(ty, out),
+            (Value::String(s), &Type::TIMESTAMP) => NaiveDateTime::parse_from_str(s, NAIVE_FORMAT)?.to_sql(ty, out),
+            (Value::String(s), &Type::DATE) => s.parse::<NaiveDate>()?.to_sql(ty, out),
+            (Value::String(s), ty) if <String as ToSql>::accepts(ty) => s.to_sql(ty, out),
+            (value, ty) => Err(format!("cursor value {} can't be compared with a {} column", value, ty).into()),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::INT2 | Type::INT4 | Type::INT8 | Type::FLOAT4 | Type::FLOAT8 | Type::BOOL
+            | Type::TIMESTAMPTZ | Type::TIMESTAMP | Type::DATE) || <String as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+fn as_i64(n: &Number) -> Result<i64, Box<dyn Error + Sync + Send>> {
+    n.as_i64().ok_or_else(|| format!("cursor value {} is not an integer", n).into())
+}
+
+fn as_f64(n: &Number) -> Result<f64, Box<dyn Error + Sync + Send>> {
+    n.as_f64().ok_or_else(|| format!("cursor value {} is not a number", n).into())
+}
+
+/// Read a column as a cursor value. Qualified columns like users.id are read by their last part
+fn row_value(row: &Row, column: &str) -> Result<Value, GenericError> {
+    let name = column.rsplit('.').next().unwrap_or(column);
+    let idx = match row.columns().iter().position(|c| c.name() == name) {
+        Some(idx) => idx,
+        None => return Err(SimpleError{message: format!("ordering column \"{}\" must be selected to paginate", column)}.into()),
+    };
+    let ty = row.columns()[idx].type_();
+    let value = match *ty {
+        Type::INT2 => row.try_get::<_, Option<i16>>(idx)?.map(Value::from),
+        Type::INT4 => row.try_get::<_, Option<i32>>(idx)?.map(Value::from),
+        Type::INT8 => row.try_get::<_, Option<i64>>(idx)?.map(Value::from),
+        Type::FLOAT4 => row.try_get::<_, Option<f32>>(idx)?.and_then(|f| Number::from_f64(f as f64)).map(Value::Number),
+        Type::FLOAT8 => row.try_get::<_, Option<f64>>(idx)?.and_then(Number::from_f64).map(Value::Number),
+        Type::BOOL => row.try_get::<_, Option<bool>>(idx)?.map(Value::from),
+        Type::TIMESTAMPTZ => row.try_get::<_, Option<DateTime<Utc>>>(idx)?.map(|t| Value::from(t.to_rfc3339_opts(SecondsFormat::AutoSi, true))),
+        Type::TIMESTAMP => row.try_get::<_, Option<NaiveDateTime>>(idx)?.map(|t| Value::from(t.format(NAIVE_FORMAT).to_string())),
+        Type::DATE => row.try_get::<_, Option<NaiveDate>>(idx)?.map(|d| Value::from(d.to_string())),
+        _ if <String as ToSql>::accepts(ty) => row.try_get::<_, Option<String>>(idx)?.map(Value::from),
+        _ => return Err(SimpleError{message: format!("can't paginate on \"{}\", a {} column", column, ty)}.into()),
+    };
+    match value {
+        Some(value) => Ok(value),
+        None => Err(SimpleError{message: format!("ordering column \"{}\" is NULL or not finite, so it can't be used in a cursor", column)}.into()),
+    }
+}
+
+
+/// Fetch a page of a query ordered by columns, all in the same direction, starting from a cursor
+/// taken from a previous page (or the first page if there is none). query should have its conditions
+/// but no ordering, limit or offset, which paginate adds
+pub async fn paginate<T, C: Queryable>(client: &C, query: QueryBuilder, columns: &[&str], order: Order, cursor: Option<&str>, page_size: usize, rowfunc: &(dyn Fn(&Row) -> T + Sync)) -> Result<Page<T>, GenericError> {
+    if page_size == 0 {
+        return Err(SimpleError::from_str("page_size must be at least 1").into())
+    }
+    let cursor = cursor.map(Cursor::decode).transpose()?;
+    let direction = match (order, cursor.as_ref().is_some_and(|c| c.backward)) {
+        (order, false) => order,
+        (Order::Asc, true) => Order::Desc,
+        (Order::Desc, true) => Order::Asc,
+    };
+    let mut query = query.sortable(columns);
+    for column in columns {
+        query = query.order_by(column, direction);
+    }
+    if let Some(cursor) = &cursor {
+        query = query.after(cursor.values.iter().map(|v| Box::new(CursorParam(v.clone())) as Param).collect());
+    }
+    let query = query.limit(page_size as i64 + 1);
+    let (sql, params) = query.build()?;
+    let rows = client.query_rows(&sql, &params).await?;
+    page(rows, page_size, cursor.as_ref(),
+        |row| columns.iter().map(|column| row_value(row, column)).collect(),
+        |row| rowfunc(&row))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::pool_no_tls_from_env;
+
+    #[test]
+    fn encode_decode() {
+        let cursor = Cursor{values: vec![Value::from("2024-01-02T03:04:05.123456Z"), Value::from(42)], backward: true};
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::decode("not a cursor!").is_err());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode("[1, 2]")).is_err());
+        // timestamps without a time zone round trip with or without fractional seconds
+        for s in ["2024-01-01T00:00:00", "2024-01-01T00:00:00.5"] {
+            let t = NaiveDateTime::parse_from_str(s, NAIVE_FORMAT).unwrap();
+            assert_eq!(NaiveDateTime::parse_from_str(&t.format(NAIVE_FORMAT).to_string(), NAIVE_FORMAT).unwrap(), t);
+        }
+    }
+
+    #[test]
+    fn page_through_rows() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_paginate;
+                CREATE TABLE _nexum_test_paginate (id INT PRIMARY KEY, created_at TIMESTAMPTZ NOT NULL, day DATE NOT NULL);
+                INSERT INTO _nexum_test_paginate SELECT n, '2024-01-01'::timestamptz + (n / 2) * interval '1.5 seconds', '2024-01-01'::date + n / 3
+                    FROM generate_series(1, 7) AS n").await.unwrap();
+            let query = || QueryBuilder::new("SELECT id, created_at, day FROM _nexum_test_paginate").where_op("id", crate::postgres::Op::Le, 6);
+            let columns = ["created_at", "day", "_nexum_test_paginate.id"];
+            let fetch = |cursor: Option<String>| {
+                let client = &client;
+                async move {
+                    paginate(client, query(), &columns, Order::Desc, cursor.as_deref(), 4, &|row| row.get::<_, i32>("id")).await.unwrap()
+                }
+            };
+            let first = fetch(None).await;
+            assert_eq!(first.items, vec![6, 5, 4, 3]);
+            assert!(first.prev_cursor.is_none());
+            let second = fetch(first.next_cursor.clone()).await;
+            assert_eq!(second.items, vec![2, 1]);
+            assert!(second.next_cursor.is_none());
+            let back = fetch(second.prev_cursor.clone()).await;
+            assert_eq!(back.items, vec![6, 5, 4, 3]);
+            assert!(back.prev_cursor.is_none());
+            assert_eq!(fetch(back.next_cursor.clone()).await.items, vec![2, 1]);
+            // a cursor for a column of another type is rejected by Postgres rather than misread
+            let forged = Cursor{values: vec![Value::from(1), Value::from(1), Value::from(1)], backward: false};
+            assert!(paginate(&client, query(), &columns, Order::Desc, Some(&forged.encode()), 4, &|row| row.get::<_, i32>("id")).await.is_err());
+            client.batch_execute("DROP TABLE _nexum_test_paginate").await.unwrap();
+        })
+    }
+}
@@ -0,0 +1,318 @@
+//! A durable job queue stored in Postgres, for deployments without SQS.
+//! Jobs are claimed with FOR UPDATE SKIP LOCKED so any number of workers can poll the same queue
+//! without blocking each other or receiving the same job twice.
+//! A claimed job stays invisible for the visibility timeout; if it isn't acked by then it is delivered again,
+//! and once it has been delivered max_attempts times without being acked it is dead-lettered.
+//!
+//! Queue has the same push/poll_strings/poll methods as sqs::Messenger,
+//! so a worker written against one can be pointed at the other.
+//! Many queues share the one _nexum_jobs table, distinguished by name
+
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json;
+use crate::core::{GenericError, SimpleError};
+use crate::hashit::hash_string;
+use super::{Client, ConnPool, Row};
+
+/// The table every queue's jobs are stored in
+pub const JOBS_TABLE: &str = "_nexum_jobs";
+const DEFAULT_VISIBILITY_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+const DEFAULT_BATCH_SIZE: i64 = 10;
+
+
+/// A job claimed from a queue. Ack it once it has been processed
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub group_id: Option<String>,
+    pub payload: String,
+    pub priority: i32,
+    /// how many times this job has been delivered, including this time
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    /// when this delivery's visibility timeout expires. It identifies the delivery,
+    /// so ack and nack do nothing once the job has been redelivered to another worker
+    pub locked_until: DateTime<Utc>,
+}
+
+impl Job {
+    fn from_row(row: &Row) -> Self {
+        Job {
+            id: row.get("id"),
+            group_id: row.get("group_id"),
+            payload: row.get("payload"),
+            priority: row.get("priority"),
+            attempts: row.get("attempts"),
+            last_error: row.get("last_error"),
+            locked_until: row.get("visible_at"),
+        }
+    }
+
+    /// deserialize the payload
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, GenericError> {
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+
+/// A named queue of jobs in the shared jobs table, with a visibility timeout and a limit on attempts
+pub struct Queue {
+    pool: ConnPool,
+    name: String,
+    visibility_timeout: Duration,
+    max_attempts: i32,
+    batch_size: i64,
+}
+
+impl Queue {
+
+    /// Instantiate a queue, creating the jobs table if it doesn't exist yet
+    pub async fn new(pool: &ConnPool, name: &str) -> Result<Self, GenericError> {
+        let client = pool.get().await?;
+        create_table(&client).await?;
+        Ok(Queue {
+            pool: pool.clone(),
+            name: name.to_string(),
+            visibility_timeout: Duration::from_secs(DEFAULT_VISIBILITY_TIMEOUT_SECONDS),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            batch_size: DEFAULT_BATCH_SIZE,
+        })
+    }
+
+    /// How long a dequeued job stays hidden from other workers before it is delivered again
+    pub fn visibility_timeout(mut self, visibility_timeout: Duration) -> Self {
+        self.visibility_timeout = visibility_timeout;
+        self
+    }
+
+    /// How many times a job is delivered before it is dead-lettered
+    pub fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The most jobs a single dequeue returns
+    pub fn batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Add a job that becomes available after delay. Higher priority jobs are dequeued first
+    pub async fn enqueue<T: Serialize>(&self, msg: &T, priority: i32, delay: Duration) -> Result<i64, GenericError> {
+        let client = self.pool.get().await?;
+        let payload = serde_json::to_string(msg)?;
+        let query = format!("INSERT INTO {} (queue, payload, priority, visible_at)
+            VALUES ($1, $2, $3, now() + make_interval(secs => $4)) RETURNING id", JOBS_TABLE);
+        let row = client.query_one(query.as_str(), &[&self.name, &payload, &priority, &delay.as_secs_f64()]).await?;
+        Ok(row.get(0))
+    }
+
+    /// Add a job that becomes available at a specific time
+    pub async fn schedule<T: Serialize>(&self, msg: &T, priority: i32, run_at: DateTime<Utc>) -> Result<i64, GenericError> {
+        let client = self.pool.get().await?;
+        let payload = serde_json::to_string(msg)?;
+        let query = format!("INSERT INTO {} (queue, payload, priority, visible_at) VALUES ($1, $2, $3, $4) RETURNING id", JOBS_TABLE);
+        let row = client.query_one(query.as_str(), &[&self.name, &payload, &priority, &run_at]).await?;
+        Ok(row.get(0))
+    }
+
+    /// Claim up to batch_size available jobs, highest priority first then oldest first.
+    /// Jobs that have already been delivered max_attempts times are dead-lettered instead
+    pub async fn dequeue(&self) -> Result<Vec<Job>, GenericError> {
+        let client = self.pool.get().await?;
+        let dead_letter = format!("UPDATE {} SET dead = true, last_error = COALESCE(last_error, 'visibility timeout expired')
+            WHERE queue = $1 AND NOT dead AND visible_at <= now() AND attempts >= $2", JOBS_TABLE);
+        client.execute(dead_letter.as_str(), &[&self.name, &self.max_attempts]).await?;
+        let claim = format!("UPDATE {t} SET attempts = attempts + 1, visible_at = now() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM {t} WHERE queue = $1 AND NOT dead AND visible_at <= now()
+                ORDER BY priority DESC, visible_at, id LIMIT $3 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, group_id, payload, priority, attempts, last_error, visible_at", t = JOBS_TABLE);
+        let rows = client.query(claim.as_str(), &[&self.name, &self.visibility_timeout.as_secs_f64(), &self.batch_size]).await?;
+        let mut jobs: Vec<Job> = rows.iter().map(Job::from_row).collect();
+        // RETURNING doesn't preserve the subquery's order
+        jobs.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.id.cmp(&b.id)));
+        Ok(jobs)
+    }
+
+    /// Remove a job that has been processed.
+    /// Returns false if the job has since been redelivered, in which case it is left for the worker that has it now
+    pub async fn ack(&self, job: &Job) -> Result<bool, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("DELETE FROM {} WHERE id = $1 AND visible_at = $2 AND NOT dead", JOBS_TABLE);
+        let n = client.execute(query.as_str(), &[&job.id, &job.locked_until]).await?;
+        Ok(n > 0)
+    }
+
+    /// Give a job back to be retried after retry_delay, or dead-letter it if it has run out of attempts.
+    /// Returns false if the job has since been redelivered, like ack
+    pub async fn nack(&self, job: &Job, retry_delay: Duration, error: Option<&str>) -> Result<bool, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("UPDATE {} SET visible_at = now() + make_interval(secs => $3),
+            dead = attempts >= $4, last_error = COALESCE($5, last_error) WHERE id = $1 AND visible_at = $2 AND NOT dead", JOBS_TABLE);
+        let n = client.execute(query.as_str(), &[&job.id, &job.locked_until, &retry_delay.as_secs_f64(), &self.max_attempts, &error]).await?;
+        Ok(n > 0)
+    }
+
+    /// List the jobs that have been dead-lettered
+    pub async fn dead_letters(&self) -> Result<Vec<Job>, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("SELECT id, group_id, payload, priority, attempts, last_error, visible_at FROM {} WHERE queue = $1 AND dead ORDER BY id", JOBS_TABLE);
+        let rows = client.query(query.as_str(), &[&self.name]).await?;
+        Ok(rows.iter().map(Job::from_row).collect())
+    }
+
+    /// Put a dead-lettered job back on the queue with its attempts reset. A job of another queue is left alone
+    pub async fn retry_dead(&self, id: i64) -> Result<(), GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("UPDATE {} SET dead = false, attempts = 0, visible_at = now() WHERE id = $1 AND queue = $2 AND dead", JOBS_TABLE);
+        client.execute(query.as_str(), &[&id, &self.name]).await?;
+        Ok(())
+    }
+
+    /// How many jobs are waiting or in flight, not counting dead letters
+    pub async fn count(&self) -> Result<i64, GenericError> {
+        let client = self.pool.get().await?;
+        let query = format!("SELECT COUNT(*) FROM {} WHERE queue = $1 AND NOT dead", JOBS_TABLE);
+        Ok(client.query_one(query.as_str(), &[&self.name]).await?.get(0))
+    }
+
+    /// publish a message (could be a string or serializable struct) to the queue with a given group_id
+    /// This mirrors sqs::Messenger::push, returning the job id as a string
+    pub async fn push<T: Serialize>(&self, msg: &T, group_id: &str) -> Result<String, GenericError> {
+        let client = self.pool.get().await?;
+        let payload = serde_json::to_string(msg)?;
+        let query = format!("INSERT INTO {} (queue, group_id, payload) VALUES ($1, $2, $3) RETURNING id", JOBS_TABLE);
+        let row = client.query_one(query.as_str(), &[&self.name, &group_id, &payload]).await?;
+        let id: i64 = row.get(0);
+        Ok(id.to_string())
+    }
+
+    /// Return the payloads of jobs as strings.
+    /// If delete_on_receipt is false the jobs are redelivered once the visibility timeout expires, just like SQS
+    pub async fn poll_strings(&self, delete_on_receipt: bool) -> Result<Vec<String>, GenericError> {
+        let jobs = self.dequeue().await?;
+        if delete_on_receipt {
+            for job in &jobs {
+                self.ack(job).await?;
+            }
+        }
+        Ok(jobs.into_iter().map(|job| job.payload).collect())
+    }
+
+    /// Return the payloads of jobs as deserializable structs
+    pub async fn poll<T: DeserializeOwned>(&self, delete_on_receipt: bool) -> Result<Vec<T>, GenericError> {
+        let mut resp = Vec::new();
+        for body in self.poll_strings(delete_on_receipt).await? {
+            let jz: T = match serde_json::from_str(&body) {
+                Ok(val) => val,
+                Err(_) => {
+                    println!("ERROR! Unable to deserialize the desired struct from '{}'", body);
+                    return Err(SimpleError{message:"JSON dserialization error".to_string()}.into())
+                }
+            };
+            resp.push(jz)
+        }
+        Ok(resp)
+    }
+}
+
+
+/// create the jobs table and its index if they don't exist
+/// CREATE TABLE IF NOT EXISTS can still fail when two sessions run it at once, so take a lock first
+async fn create_table(client: &Client) -> Result<(), GenericError> {
+    client.batch_execute(&format!("
+        BEGIN;
+        SELECT pg_advisory_xact_lock({lock});
+        CREATE TABLE IF NOT EXISTS {t} (
+            id BIGSERIAL PRIMARY KEY,
+            queue TEXT NOT NULL,
+            group_id TEXT,
+            payload TEXT NOT NULL,
+            priority INT NOT NULL DEFAULT 0,
+            attempts INT NOT NULL DEFAULT 0,
+            visible_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            dead BOOLEAN NOT NULL DEFAULT false,
+            last_error TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE INDEX IF NOT EXISTS {t}_ready ON {t} (queue, priority DESC, visible_at) WHERE NOT dead;
+        COMMIT;
+    ", t = JOBS_TABLE, lock = hash_string(JOBS_TABLE) as i64)).await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::pool_no_tls_from_env;
+
+    async fn fresh_queue(pool: &ConnPool, name: &str) -> Queue {
+        let queue = Queue::new(pool, name).await.unwrap();
+        let client = pool.get().await.unwrap();
+        client.execute(format!("DELETE FROM {} WHERE queue = $1", JOBS_TABLE).as_str(), &[&name]).await.unwrap();
+        queue
+    }
+
+    #[test]
+    fn priority_and_schedule() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let queue = fresh_queue(&pool, "_test_priority").await;
+            queue.enqueue(&"low", 0, Duration::ZERO).await.unwrap();
+            queue.enqueue(&"high", 10, Duration::ZERO).await.unwrap();
+            queue.enqueue(&"later", 99, Duration::from_secs(3600)).await.unwrap();
+            queue.schedule(&"past", 5, Utc::now() - chrono::Duration::seconds(5)).await.unwrap();
+            let msgs: Vec<String> = queue.poll(true).await.unwrap();
+            assert_eq!(msgs, vec!["high", "past", "low"]);
+            // the delayed job is still waiting
+            assert_eq!(queue.count().await.unwrap(), 1);
+        })
+    }
+
+    #[test]
+    fn visibility_nack_and_dead_letter() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let queue = fresh_queue(&pool, "_test_dead_letter").await
+                .visibility_timeout(Duration::from_millis(200))
+                .max_attempts(2);
+            let id: i64 = queue.push(&"flaky", "group").await.unwrap().parse().unwrap();
+            // once claimed a job is invisible, until it is nacked
+            let first = queue.dequeue().await.unwrap().remove(0);
+            assert_eq!(first.attempts, 1);
+            assert!(queue.dequeue().await.unwrap().is_empty());
+            assert!(queue.nack(&first, Duration::ZERO, Some("boom")).await.unwrap());
+            // the second delivery times out, which exhausts its attempts
+            let jobs = queue.dequeue().await.unwrap();
+            assert_eq!((jobs[0].attempts, jobs[0].last_error.as_deref()), (2, Some("boom")));
+            // the first delivery is over, so its worker can no longer ack or nack the job
+            assert!(!queue.ack(&first).await.unwrap());
+            assert!(!queue.nack(&first, Duration::ZERO, None).await.unwrap());
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert!(queue.dequeue().await.unwrap().is_empty());
+            let dead = queue.dead_letters().await.unwrap();
+            assert_eq!(dead.len(), 1);
+            // another queue can't revive it
+            let other = fresh_queue(&pool, "_test_dead_letter_other").await;
+            other.retry_dead(id).await.unwrap();
+            assert_eq!(queue.dead_letters().await.unwrap().len(), 1);
+            // a retried dead letter can be processed and acked
+            queue.retry_dead(id).await.unwrap();
+            let jobs = queue.dequeue().await.unwrap();
+            assert_eq!(jobs[0].deserialize::<String>().unwrap(), "flaky");
+            assert!(!queue.ack(&first).await.unwrap());
+            assert!(queue.ack(&jobs[0]).await.unwrap());
+            assert_eq!(queue.count().await.unwrap(), 0);
+            assert!(queue.dead_letters().await.unwrap().is_empty());
+        })
+    }
+}
@@ -0,0 +1,272 @@
+//! A minimal client for the streaming replication protocol, which tokio_postgres doesn't speak.
+//! The connection is opened with replication=database, so it takes SQL and replication commands as simple queries,
+//! until START_REPLICATION switches it to CopyBoth mode: WAL is received as XLogData messages, and the client
+//! reports how far it has got with standby status updates, which is what moves a logical slot on
+
+use std::{error::Error, fmt, time::{SystemTime, UNIX_EPOCH}};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use postgres_protocol::{authentication::{md5_hash, sasl::{ChannelBinding, ScramSha256, SCRAM_SHA_256}}, message::frontend};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::{TcpStream, UnixStream}};
+use tokio_postgres::types::PgLsn;
+use crate::core::{GenericError, SimpleError};
+use super::{listen::quote_ident, SimpleConfig};
+
+/// Microseconds between the Unix epoch and the Postgres epoch (2000-01-01)
+pub(crate) const PG_EPOCH_OFFSET_MICROS: i64 = 946_684_800_000_000;
+
+
+/// An ErrorResponse sent by the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerError {
+    /// the SQLSTATE, i.e. 55006 when a replication slot is in use
+    pub code: String,
+    pub message: String,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (SQLSTATE {})", self.message, self.code)
+    }
+}
+
+impl Error for ServerError {}
+
+
+/// A message received once replication has started
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplicationMessage {
+    /// WAL from wal_start on. For logical replication data is a single message of the output plugin
+    XLogData { wal_start: PgLsn, wal_end: PgLsn, data: Bytes },
+    /// the end of the WAL on the server. reply asks for a status update straight away
+    Keepalive { wal_end: PgLsn, reply: bool },
+}
+
+
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+/// A replication connection
+pub struct ReplicationConnection {
+    socket: Box<dyn Socket>,
+    read: BytesMut,
+    write: BytesMut,
+}
+
+impl ReplicationConnection {
+
+    /// Connect and authenticate, with a password (cleartext, md5 or SCRAM-SHA-256) or without
+    pub async fn connect(config: &SimpleConfig) -> Result<Self, GenericError> {
+        let socket: Box<dyn Socket> = if config.host.starts_with('/') {
+            Box::new(UnixStream::connect(format!("{}/.s.PGSQL.{}", config.host, config.port)).await?)
+        } else {
+            let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+            tcp.set_nodelay(true)?;
+            Box::new(tcp)
+        };
+        let mut conn = ReplicationConnection{socket, read: BytesMut::new(), write: BytesMut::new()};
+        let params = [("user", config.user.as_str()), ("database", config.database.as_str()), ("replication", "database")];
+        frontend::startup_message(params, &mut conn.write)?;
+        conn.flush().await?;
+        conn.authenticate(config).await?;
+        // parameter statuses and the backend key come before the connection is ready
+        loop {
+            let (tag, body) = conn.recv_message().await?;
+            match tag {
+                b'Z' => return Ok(conn),
+                b'E' => return Err(server_error(body).into()),
+                _ => (),
+            }
+        }
+    }
+
+    async fn authenticate(&mut self, config: &SimpleConfig) -> Result<(), GenericError> {
+        let mut scram = None;
+        loop {
+            let (tag, mut body) = self.recv_message().await?;
+            match tag {
+                b'R' => (),
+                b'E' => return Err(server_error(body).into()),
+                _ => continue,
+            }
+            match take(&mut body, 4)?.get_i32() {
+                0 => return Ok(()),
+                3 => frontend::password_message(config.password.as_bytes(), &mut self.write)?,
+                5 => {
+                    let salt = take(&mut body, 4)?;
+                    let hash = md5_hash(config.user.as_bytes(), config.password.as_bytes(), [salt[0], salt[1], salt[2], salt[3]]);
+                    frontend::password_message(hash.as_bytes(), &mut self.write)?;
+                },
+                10 => {
+                    if !body.split(|b| *b == 0).any(|mechanism| mechanism == SCRAM_SHA_256.as_bytes()) {
+                        return Err(SimpleError::from_str("the server offers no supported SASL mechanism").into())
+                    }
+                    let started = ScramSha256::new(config.password.as_bytes(), ChannelBinding::unsupported());
+                    frontend::sasl_initial_response(SCRAM_SHA_256, started.message(), &mut self.write)?;
+                    scram = Some(started);
+                },
+                11 => {
+                    let scram = scram.as_mut().ok_or_else(|| SimpleError::from_str("SASL continue before SASL was started"))?;
+                    scram.update(&body)?;
+                    frontend::sasl_response(scram.message(), &mut self.write)?;
+                },
+                12 => scram.as_mut().ok_or_else(|| SimpleError::from_str("SASL final before SASL was started"))?.finish(&body)?,
+                method => return Err(SimpleError{message: format!("unsupported authentication method {}", method)}.into()),
+            }
+            self.flush().await?;
+        }
+    }
+
+    /// Run a simple query or replication command, returning the text of each row
+    pub async fn simple_query(&mut self, sql: &str) -> Result<Vec<Vec<Option<String>>>, GenericError> {
+        frontend::query(sql, &mut self.write)?;
+        self.flush().await?;
+        let mut rows = Vec::new();
+        let mut error = None;
+        loop {
+            let (tag, body) = self.recv_message().await?;
+            match tag {
+                b'D' => rows.push(data_row(body)?),
+                b'E' => error = Some(server_error(body)),
+                b'Z' => break,
+                _ => (),
+            }
+        }
+        match error {
+            Some(error) => Err(error.into()),
+            None => Ok(rows),
+        }
+    }
+
+    /// Start streaming changes to the tables in a publication from a logical slot with pgoutput.
+    /// Starting at 0/0 carries on from the slot's confirmed position
+    pub async fn start_logical(&mut self, slot: &str, publication: &str, start: PgLsn) -> Result<(), GenericError> {
+        let sql = format!("START_REPLICATION SLOT {} LOGICAL {} (proto_version '1', publication_names {})",
+            quote_ident(slot), start, quote_literal(&quote_ident(publication)));
+        frontend::query(&sql, &mut self.write)?;
+        self.flush().await?;
+        let mut error = None;
+        loop {
+            let (tag, body) = self.recv_message().await?;
+            match tag {
+                b'W' => return Ok(()),
+                b'E' => error = Some(server_error(body)),
+                b'Z' => return Err(error.unwrap_or_else(|| ServerError{code: String::new(), message: "replication didn't start".to_string()}).into()),
+                _ => (),
+            }
+        }
+    }
+
+    /// Wait for the next XLogData or keepalive. This is cancel safe, nothing is lost if it is raced against a timeout
+    pub async fn recv(&mut self) -> Result<ReplicationMessage, GenericError> {
+        loop {
+            let (tag, mut body) = self.recv_message().await?;
+            match tag {
+                b'd' => match take(&mut body, 1)?[0] {
+                    b'w' => {
+                        let mut header = take(&mut body, 24)?;
+                        let wal_start = PgLsn::from(header.get_u64());
+                        let wal_end = PgLsn::from(header.get_u64());
+                        return Ok(ReplicationMessage::XLogData{wal_start, wal_end, data: body})
+                    },
+                    b'k' => {
+                        let mut header = take(&mut body, 17)?;
+                        let wal_end = PgLsn::from(header.get_u64());
+                        let _sent_at = header.get_i64();
+                        return Ok(ReplicationMessage::Keepalive{wal_end, reply: header.get_u8() == 1})
+                    },
+                    _ => (),
+                },
+                b'c' => return Err(SimpleError::from_str("the server ended replication").into()),
+                b'E' => return Err(server_error(body).into()),
+                _ => (),
+            }
+        }
+    }
+
+    /// Tell the server what has been received and what has been flushed. A logical slot's confirmed position is moved on
+    /// to flushed, after which a restarted consumer starts there and Postgres can recycle the WAL before it
+    pub async fn send_status(&mut self, received: PgLsn, flushed: PgLsn, reply: bool) -> Result<(), GenericError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as i64 - PG_EPOCH_OFFSET_MICROS;
+        let mut status = BytesMut::with_capacity(34);
+        status.put_u8(b'r');
+        status.put_u64(received.into());
+        status.put_u64(flushed.into());
+        status.put_u64(flushed.into());
+        status.put_i64(now);
+        status.put_u8(reply as u8);
+        frontend::CopyData::new(status)?.write(&mut self.write);
+        self.flush().await
+    }
+
+    /// Say goodbye to the server. Dropping the connection closes it too, just less politely
+    pub async fn close(mut self) {
+        frontend::terminate(&mut self.write);
+        let _ = self.flush().await;
+    }
+
+    async fn flush(&mut self) -> Result<(), GenericError> {
+        self.socket.write_all(&self.write).await?;
+        self.write.clear();
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    /// The tag and body of the next message, buffering what has been read so far across cancellations
+    async fn recv_message(&mut self) -> Result<(u8, Bytes), GenericError> {
+        loop {
+            if self.read.len() >= 5 {
+                let len = i32::from_be_bytes([self.read[1], self.read[2], self.read[3], self.read[4]]);
+                if len < 4 {
+                    return Err(SimpleError{message: format!("invalid message length {} from the server", len)}.into())
+                }
+                if self.read.len() > len as usize {
+                    let mut message = self.read.split_to(len as usize + 1).freeze();
+                    let tag = message.get_u8();
+                    message.advance(4);
+                    return Ok((tag, message))
+                }
+            }
+            if self.socket.read_buf(&mut self.read).await? == 0 {
+                return Err(SimpleError::from_str("the server closed the replication connection").into())
+            }
+        }
+    }
+}
+
+fn take(buf: &mut Bytes, n: usize) -> Result<Bytes, GenericError> {
+    if buf.len() < n {
+        return Err(SimpleError::from_str("truncated message from the server").into())
+    }
+    Ok(buf.split_to(n))
+}
+
+fn data_row(mut body: Bytes) -> Result<Vec<Option<String>>, GenericError> {
+    let columns = take(&mut body, 2)?.get_i16();
+    let mut row = Vec::new();
+    for _ in 0..columns {
+        let len = take(&mut body, 4)?.get_i32();
+        row.push(match len {
+            -1 => None,
+            len => Some(String::from_utf8(take(&mut body, len as usize)?.to_vec())?),
+        });
+    }
+    Ok(row)
+}
+
+fn server_error(body: Bytes) -> ServerError {
+    let mut error = ServerError{code: String::new(), message: String::new()};
+    for field in body.split(|b| *b == 0).filter(|field| !field.is_empty()) {
+        let value = String::from_utf8_lossy(&field[1..]).into_owned();
+        match field[0] {
+            b'C' => error.code = value,
+            b'M' => error.message = value,
+            _ => (),
+        }
+    }
+    error
+}
+
+pub(crate) fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
@@ -0,0 +1,215 @@
+//! RoutedPool spreads reads across read replicas while sending writes and transactions to the primary.
+//! Replicas are health checked by measuring how far behind the primary they are with pg_last_xact_replay_timestamp();
+//! one that is lagging by more than max_lag or can't be reached stops receiving reads until it recovers,
+//! and if no replica is healthy reads go to the primary instead.
+//! Reads that must see a write that was just made should use the primary, since replication is asynchronous
+
+use std::{marker::Sync, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::Duration};
+use futures::future::BoxFuture;
+use tokio::task::JoinHandle;
+use tokio_postgres::types::ToSql;
+use crate::core::GenericError;
+use super::{get_one, get_opt, get_vec, execute, transaction, Client, ConnPool, IsolationLevel, Row, Transaction};
+
+
+/// How reads are spread across healthy replicas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    /// take turns
+    RoundRobin,
+    /// pick the replica with the fewest connections in use
+    LeastConnections,
+}
+
+struct Replica {
+    pool: ConnPool,
+    healthy: AtomicBool,
+}
+
+struct Inner {
+    primary: ConnPool,
+    replicas: Vec<Replica>,
+    balance: Balance,
+    max_lag: Duration,
+    next: AtomicUsize,
+}
+
+/// A primary pool and any number of replica pools. It can be cloned for thread-safe http servers etc.
+#[derive(Clone)]
+pub struct RoutedPool {
+    inner: Arc<Inner>,
+}
+
+impl RoutedPool {
+
+    /// Instantiate a new RoutedPool. Replicas start out healthy until a health check says otherwise
+    pub fn new(primary: ConnPool, replicas: Vec<ConnPool>, balance: Balance, max_lag: Duration) -> Self {
+        let replicas = replicas.into_iter().map(|pool| Replica{pool, healthy: AtomicBool::new(true)}).collect();
+        RoutedPool{inner: Arc::new(Inner{primary, replicas, balance, max_lag, next: AtomicUsize::new(0)})}
+    }
+
+    /// The primary pool, for anything that writes
+    pub fn primary(&self) -> &ConnPool {
+        &self.inner.primary
+    }
+
+    /// Get a connection to the primary
+    pub async fn write(&self) -> Result<Client, GenericError> {
+        Ok(self.inner.primary.get().await?)
+    }
+
+    /// Get a connection for reading, from a healthy replica if there is one and otherwise from the primary.
+    /// A replica that can't hand out a connection is marked unhealthy
+    pub async fn read(&self) -> Result<Client, GenericError> {
+        if let Some(replica) = self.choose_replica().await {
+            match replica.pool.get().await {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    println!("ERROR! could not connect to a replica, falling back to the primary: {}", e);
+                    replica.healthy.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+        self.write().await
+    }
+
+    async fn choose_replica(&self) -> Option<&Replica> {
+        let healthy: Vec<&Replica> = self.inner.replicas.iter().filter(|r| r.healthy.load(Ordering::Relaxed)).collect();
+        if healthy.is_empty() {
+            return None
+        }
+        match self.inner.balance {
+            Balance::RoundRobin => {
+                let i = self.inner.next.fetch_add(1, Ordering::Relaxed);
+                Some(healthy[i % healthy.len()])
+            },
+            Balance::LeastConnections => {
+                let mut best: Option<(&Replica, u64)> = None;
+                for replica in healthy {
+                    let in_use = replica.pool.state().await.in_use;
+                    let fewer = match best {
+                        Some((_, fewest)) => in_use < fewest,
+                        None => true,
+                    };
+                    if fewer {
+                        best = Some((replica, in_use));
+                    }
+                }
+                best.map(|(replica, _)| replica)
+            },
+        }
+    }
+
+    /// Check every replica's lag, marking each healthy or not, and return whether each is healthy
+    pub async fn check_health(&self) -> Vec<bool> {
+        let mut results = Vec::new();
+        for replica in &self.inner.replicas {
+            let healthy = match replica_lag(&replica.pool).await {
+                Ok(lag) => lag <= self.inner.max_lag,
+                Err(_) => false,
+            };
+            replica.healthy.store(healthy, Ordering::Relaxed);
+            results.push(healthy);
+        }
+        results
+    }
+
+    /// Check replica health every interval in the background, until the returned handle is aborted
+    pub fn spawn_health_checks(&self, interval: Duration) -> JoinHandle<()> {
+        let routed = self.clone();
+        tokio::spawn(async move {
+            loop {
+                routed.check_health().await;
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// get_opt on a replica
+    pub async fn get_opt<'a, T>(&self, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Option<T>, GenericError> {
+        let client = self.read().await?;
+        get_opt(&client, query, rowfunc, params).await
+    }
+
+    /// get_one on a replica
+    pub async fn get_one<'a, T>(&self, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params: &'a [&'a (dyn ToSql + Sync)]) -> Result<T, GenericError> {
+        let client = self.read().await?;
+        get_one(&client, query, rowfunc, params).await
+    }
+
+    /// get_vec on a replica
+    pub async fn get_vec<'a, T>(&self, query: &'a str, rowfunc: &'a (dyn Fn(&Row) -> T + Sync), params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Vec<T>, GenericError> {
+        let client = self.read().await?;
+        get_vec(&client, query, rowfunc, params).await
+    }
+
+    /// execute on the primary
+    pub async fn execute<'a>(&self, query: &'a str, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<u64, GenericError> {
+        let client = self.write().await?;
+        execute(&client, query, params).await
+    }
+
+    /// run a transaction on the primary
+    pub async fn transaction<T, F>(&self, isolation: IsolationLevel, f: F) -> Result<T, GenericError>
+    where
+        F: for<'t, 'c> FnMut(&'t mut Transaction<'c>) -> BoxFuture<'t, Result<T, GenericError>>,
+    {
+        transaction(&self.inner.primary, isolation, f).await
+    }
+}
+
+
+/// How far a replica is behind its primary. A server that isn't a replica, or a replica that has replayed
+/// everything it has received, has no lag
+pub async fn replica_lag(pool: &ConnPool) -> Result<Duration, GenericError> {
+    let client = pool.get().await?;
+    let row = client.query_one("SELECT CASE
+            WHEN NOT pg_is_in_recovery() OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0
+            ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0)
+        END::float8", &[]).await?;
+    let seconds: f64 = row.get(0);
+    Ok(Duration::from_secs_f64(seconds.max(0.0)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::{pool_no_tls_from_env, Pool, PgConnectionManager, NoTls, SimpleConfig};
+
+    #[test]
+    fn route_and_fall_back() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // the test server stands in for both the primary and a replica
+            let primary = pool_no_tls_from_env().await.unwrap();
+            let replica = pool_no_tls_from_env().await.unwrap();
+            let mut unreachable = SimpleConfig::new_from_env();
+            unreachable.port = 1;
+            let broken = Pool::builder().get_timeout(Some(Duration::from_secs(1))).build(PgConnectionManager::new(unreachable.pg_config(), NoTls));
+            let routed = RoutedPool::new(primary, vec![replica, broken], Balance::RoundRobin, Duration::from_secs(5));
+            assert_eq!(routed.check_health().await, vec![true, false]);
+            for _ in 0..3 {
+                let one: i32 = routed.get_one("SELECT 1", &|row| row.get(0), &[]).await.unwrap();
+                assert_eq!(one, 1);
+            }
+            // a replica that went down since the last health check is marked unhealthy by the read that finds out,
+            // which is served by the primary instead
+            let only_broken = RoutedPool::new(routed.primary().clone(), vec![routed.inner.replicas[1].pool.clone()], Balance::RoundRobin, Duration::from_secs(5));
+            assert!(only_broken.inner.replicas[0].healthy.load(Ordering::Relaxed));
+            let client = only_broken.read().await.unwrap();
+            assert_eq!(only_broken.primary().state().await.in_use, 1);
+            let one: i32 = get_one(&client, "SELECT 1", &|row| row.get(0), &[]).await.unwrap();
+            assert_eq!(one, 1);
+            drop(client);
+            assert!(!only_broken.inner.replicas[0].healthy.load(Ordering::Relaxed));
+            // with no healthy replicas, reads go to the primary
+            let routed = RoutedPool::new(routed.primary().clone(), vec![], Balance::LeastConnections, Duration::from_secs(5));
+            let n = routed.get_vec("SELECT generate_series(1, 3)", &|row| row.get::<_, i32>(0), &[]).await.unwrap();
+            assert_eq!(n, vec![1, 2, 3]);
+            let lag = replica_lag(routed.primary()).await.unwrap();
+            assert_eq!(lag, Duration::ZERO);
+        })
+    }
+}
@@ -0,0 +1,412 @@
+//! A prepared statement cache for every pooled connection. Prepared statements belong to the connection
+//! that prepared them, so a CachedPool hands out a CachedClient: the tokio_postgres Client plus a cache of
+//! its statements keyed by query text. Given a CachedConn, get_opt, get_vec, execute etc. prepare each query
+//! once per connection and reuse the statement after that, saving a round trip on every call.
+//! They take a plain Client from a ConnPool just the same, which prepares every query as before.
+//! The cache holds at most capacity statements, evicting the least recently used.
+//! If a table changes underneath a cached statement (i.e. a column is added to a table queried with SELECT *),
+//! Postgres rejects it with "cached plan must not change result type"; the statement is then dropped from
+//! the cache, re-prepared and run again. That retry can't help inside a transaction, which the error aborts.
+//! Hits and misses are counted in CacheMetrics, which are shared by every connection from the same pool
+
+use std::{collections::HashMap, fmt, marker::Sync, ops::{Deref, DerefMut}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}};
+use mobc::{async_trait, Manager};
+use tokio_postgres::{types::ToSql, Config, NoTls, RowStream, Statement};
+use super::{is_sqlstate, ErrorTKPG, Row, SqlState};
+
+
+/// How many statements each connection keeps prepared by default
+pub const STATEMENT_CACHE_SIZE: usize = 256;
+
+
+/// A connection get_opt, get_vec, execute etc. can run queries on, either a plain Client,
+/// a CachedConn that reuses its prepared statements, or a Transaction (or savepoint) on either
+#[async_trait]
+pub trait Queryable: Sync {
+    async fn query_rows(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, ErrorTKPG>;
+    async fn query_row_stream(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<RowStream, ErrorTKPG>;
+    async fn execute_query(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, ErrorTKPG>;
+}
+
+#[async_trait]
+impl Queryable for tokio_postgres::Client {
+    async fn query_rows(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, ErrorTKPG> {
+        self.query(query, params).await
+    }
+
+    async fn query_row_stream(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<RowStream, ErrorTKPG> {
+        self.query_raw(query, params.iter().map(|p| *p as &dyn ToSql)).await
+    }
+
+    async fn execute_query(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, ErrorTKPG> {
+        self.execute(query, params).await
+    }
+}
+
+#[async_trait]
+impl Queryable for tokio_postgres::Transaction<'_> {
+    async fn query_rows(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, ErrorTKPG> {
+        self.query(query, params).await
+    }
+
+    async fn query_row_stream(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<RowStream, ErrorTKPG> {
+        self.query_raw(query, params.iter().map(|p| *p as &dyn ToSql)).await
+    }
+
+    async fn execute_query(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, ErrorTKPG> {
+        self.execute(query, params).await
+    }
+}
+
+#[async_trait]
+impl Queryable for CachedClient {
+    async fn query_rows(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, ErrorTKPG> {
+        self.query_cached(query, params).await
+    }
+
+    async fn query_row_stream(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<RowStream, ErrorTKPG> {
+        self.query_raw_cached(query, params).await
+    }
+
+    async fn execute_query(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, ErrorTKPG> {
+        self.execute_cached(query, params).await
+    }
+}
+
+/// Pooled connections, i.e. Client and CachedConn, query through the connection they hold
+#[async_trait]
+impl<M: Manager> Queryable for mobc::Connection<M>
+where
+    M::Connection: Queryable,
+{
+    async fn query_rows(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, ErrorTKPG> {
+        (**self).query_rows(query, params).await
+    }
+
+    async fn query_row_stream(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<RowStream, ErrorTKPG> {
+        (**self).query_row_stream(query, params).await
+    }
+
+    async fn execute_query(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, ErrorTKPG> {
+        (**self).execute_query(query, params).await
+    }
+}
+
+
+/// Statement cache counters, shared by every connection from a pool
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl CacheMetrics {
+
+    /// Queries that reused a prepared statement
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Queries that had to be prepared
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Statements dropped to make room for others
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Statements dropped because their cached plan went stale
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Ordering::Relaxed)
+    }
+
+    /// The fraction of queries that reused a prepared statement, between 0 and 1
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.hits();
+        match hits + self.misses() {
+            0 => 0.0,
+            total => hits as f64 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for CacheMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hits: {}, misses: {}, hit rate: {:.1}%, evictions: {}, invalidations: {}",
+            self.hits(), self.misses(), self.hit_rate() * 100.0, self.evictions(), self.invalidations())
+    }
+}
+
+
+struct Cached {
+    statement: Statement,
+    last_used: u64,
+}
+
+/// Statements keyed by query text, with a counter to find the least recently used
+struct StatementCache {
+    entries: HashMap<String, Cached>,
+    capacity: usize,
+    tick: u64,
+}
+
+impl StatementCache {
+
+    fn get(&mut self, query: &str) -> Option<Statement> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(query).map(|cached| {
+            cached.last_used = tick;
+            cached.statement.clone()
+        })
+    }
+
+    /// insert a statement, returning whether another had to be evicted to make room
+    fn insert(&mut self, query: &str, statement: Statement) -> bool {
+        if self.capacity == 0 {
+            return false
+        }
+        let mut evicted = false;
+        if !self.entries.contains_key(query) && self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().min_by_key(|(_, cached)| cached.last_used).map(|(query, _)| query.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+                evicted = true;
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(query.to_string(), Cached{statement, last_used: self.tick});
+        evicted
+    }
+}
+
+
+/// True if a statement failed because its cached plan no longer matches the tables it reads,
+/// or because the server has forgotten it
+fn is_stale(e: &ErrorTKPG) -> bool {
+    let changed_result = is_sqlstate(e, &SqlState::FEATURE_NOT_SUPPORTED)
+        && e.as_db_error().is_some_and(|db| db.message().contains("cached plan must not change result type"));
+    changed_result || is_sqlstate(e, &SqlState::INVALID_SQL_STATEMENT_NAME)
+}
+
+
+/// A connection with its own prepared statement cache. It derefs to the tokio_postgres Client,
+/// so every Client method is available as usual
+pub struct CachedClient {
+    client: tokio_postgres::Client,
+    statements: Mutex<StatementCache>,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl CachedClient {
+
+    /// Wrap a client, caching up to capacity statements (0 disables caching)
+    pub fn new(client: tokio_postgres::Client, capacity: usize, metrics: Arc<CacheMetrics>) -> Self {
+        let statements = Mutex::new(StatementCache{entries: HashMap::new(), capacity, tick: 0});
+        CachedClient{client, statements, metrics}
+    }
+
+    /// Return the cached statement for a query, preparing and caching it if there isn't one
+    pub async fn prepare_cached(&self, query: &str) -> Result<Statement, ErrorTKPG> {
+        if let Some(statement) = self.statements.lock().unwrap().get(query) {
+            self.metrics.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(statement)
+        }
+        self.metrics.misses.fetch_add(1, Ordering::Relaxed);
+        let statement = self.client.prepare(query).await?;
+        if self.statements.lock().unwrap().insert(query, statement.clone()) {
+            self.metrics.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(statement)
+    }
+
+    /// Drop the cached statement for a query, if there is one
+    pub fn invalidate(&self, query: &str) {
+        if self.statements.lock().unwrap().entries.remove(query).is_some() {
+            self.metrics.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drop every cached statement, i.e. after changing the schema
+    pub fn clear_statements(&self) {
+        self.statements.lock().unwrap().entries.clear();
+    }
+
+    /// How many statements are cached on this connection
+    pub fn cached_statements(&self) -> usize {
+        self.statements.lock().unwrap().entries.len()
+    }
+
+    /// The cache counters for the pool this connection came from
+    pub fn metrics(&self) -> Arc<CacheMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Like Client::query, using the cached statement for the query
+    pub async fn query_cached(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, ErrorTKPG> {
+        let statement = self.prepare_cached(query).await?;
+        match self.client.query(&statement, params).await {
+            Err(e) if is_stale(&e) => {
+                self.invalidate(query);
+                let statement = self.prepare_cached(query).await?;
+                self.client.query(&statement, params).await
+            },
+            result => result,
+        }
+    }
+
+    /// Like Client::query_raw, using the cached statement for the query
+    pub async fn query_raw_cached(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<RowStream, ErrorTKPG> {
+        let statement = self.prepare_cached(query).await?;
+        match self.client.query_raw(&statement, params.iter().map(|p| *p as &dyn ToSql)).await {
+            Err(e) if is_stale(&e) => {
+                self.invalidate(query);
+                let statement = self.prepare_cached(query).await?;
+                self.client.query_raw(&statement, params.iter().map(|p| *p as &dyn ToSql)).await
+            },
+            result => result,
+        }
+    }
+
+    /// Like Client::execute, using the cached statement for the query
+    pub async fn execute_cached(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, ErrorTKPG> {
+        let statement = self.prepare_cached(query).await?;
+        match self.client.execute(&statement, params).await {
+            Err(e) if is_stale(&e) => {
+                self.invalidate(query);
+                let statement = self.prepare_cached(query).await?;
+                self.client.execute(&statement, params).await
+            },
+            result => result,
+        }
+    }
+
+    /// Unwrap the tokio_postgres Client, discarding the cache
+    pub fn into_inner(self) -> tokio_postgres::Client {
+        self.client
+    }
+}
+
+impl Deref for CachedClient {
+    type Target = tokio_postgres::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for CachedClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
+
+/// A mobc Manager for NoTls connections which hands out CachedClients
+pub struct CachingManager {
+    config: Config,
+    capacity: usize,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl CachingManager {
+
+    /// Instantiate a new CachingManager, caching up to capacity statements on each connection
+    pub fn new(config: Config, capacity: usize) -> Self {
+        CachingManager{config, capacity, metrics: Arc::new(CacheMetrics::default())}
+    }
+
+    /// The cache counters for every connection this manager creates
+    pub fn metrics(&self) -> Arc<CacheMetrics> {
+        self.metrics.clone()
+    }
+}
+
+#[async_trait]
+impl Manager for CachingManager {
+    type Connection = CachedClient;
+    type Error = ErrorTKPG;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let (client, connection) = self.config.connect(NoTls).await?;
+        mobc::spawn(connection);
+        Ok(CachedClient::new(client, self.capacity, self.metrics.clone()))
+    }
+
+    async fn check(&self, conn: Self::Connection) -> Result<Self::Connection, Self::Error> {
+        conn.simple_query("").await?;
+        Ok(conn)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::postgres::{cached_pool_no_tls_from_env, get_one, get_vec, pool_no_tls_from_env, Pool, SimpleConfig};
+
+    #[test]
+    fn cache_statements() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // one connection caching two statements, so every query below shares a cache
+            let manager = CachingManager::new(SimpleConfig::new_from_env().pg_config(), 2);
+            let metrics = manager.metrics();
+            let pool = Pool::builder().max_open(1).build(manager);
+            let client = pool.get().await.unwrap();
+            for i in 0..3 {
+                let n: i32 = get_one(&client, "SELECT $1::int + 1", &|row| row.get(0), &[&i]).await.unwrap();
+                assert_eq!(n, i + 1);
+            }
+            assert_eq!((metrics.hits(), metrics.misses()), (2, 1));
+            get_one(&client, "SELECT 2", &|row| row.get::<_, i32>(0), &[]).await.unwrap();
+            get_one(&client, "SELECT 3", &|row| row.get::<_, i32>(0), &[]).await.unwrap();
+            assert_eq!(client.cached_statements(), 2);
+            assert_eq!(metrics.evictions(), 1);
+            // the first query was least recently used, so it was evicted
+            get_one(&client, "SELECT $1::int + 1", &|row| row.get::<_, i32>(0), &[&1]).await.unwrap();
+            assert_eq!(metrics.misses(), 4);
+            assert!((metrics.hit_rate() - 2.0 / 6.0).abs() < 1e-9);
+        })
+    }
+
+    #[test]
+    fn recover_from_stale_plan() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let manager = CachingManager::new(SimpleConfig::new_from_env().pg_config(), STATEMENT_CACHE_SIZE);
+            let metrics = manager.metrics();
+            let pool = Pool::builder().max_open(1).build(manager);
+            let client = pool.get().await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_statements;
+                CREATE TABLE _nexum_test_statements (id INT); INSERT INTO _nexum_test_statements VALUES (1)").await.unwrap();
+            let query = "SELECT * FROM _nexum_test_statements";
+            let widths = get_vec(&client, query, &|row| row.len(), &[]).await.unwrap();
+            assert_eq!(widths, vec![1]);
+            client.batch_execute("ALTER TABLE _nexum_test_statements ADD COLUMN name TEXT").await.unwrap();
+            let widths = get_vec(&client, query, &|row| row.len(), &[]).await.unwrap();
+            assert_eq!(widths, vec![2]);
+            assert_eq!(metrics.invalidations(), 1);
+            client.batch_execute("DROP TABLE _nexum_test_statements").await.unwrap();
+        })
+    }
+
+    #[test]
+    fn plain_and_cached_pools() {
+        // the helpers take a connection from either kind of pool
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let plain = pool_no_tls_from_env().await.unwrap().get().await.unwrap();
+            let cached = cached_pool_no_tls_from_env().await.unwrap().get().await.unwrap();
+            let n: i32 = get_one(&plain, "SELECT $1::int * 2", &|row| row.get(0), &[&21]).await.unwrap();
+            let m: i32 = get_one(&cached, "SELECT $1::int * 2", &|row| row.get(0), &[&21]).await.unwrap();
+            assert_eq!((n, m), (42, 42));
+            assert_eq!(cached.cached_statements(), 1);
+        })
+    }
+}
@@ -0,0 +1,48 @@
+/// Generated from public._nexum_test_introspect_fixture
+/// Primary key: id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NexumTestIntrospectFixture {
+    pub id: i32,
+    #[serde(rename = "a b")]
+    pub a_b: Option<String>,
+    #[serde(rename = "a_b")]
+    pub a_b_2: String,
+    #[serde(rename = "A_B")]
+    pub a_b_3: Option<i32>,
+    pub r#type: Option<String>,
+    /// numeric has no native mapping, so it is selected as text
+    pub price: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl NexumTestIntrospectFixture {
+    /// Select every column in the order from_row() expects
+    pub const SELECT: &str = "SELECT id, \"a b\", a_b, \"A_B\", type, price::text AS price, tags FROM public._nexum_test_introspect_fixture";
+
+    /// Map a row selected with SELECT, for use as a rowfunc
+    pub fn from_row(row: &Row) -> Self {
+        NexumTestIntrospectFixture {
+            id: row.get("id"),
+            a_b: row.get("a b"),
+            a_b_2: row.get("a_b"),
+            a_b_3: row.get("A_B"),
+            r#type: row.get("type"),
+            price: row.get("price"),
+            tags: row.get("tags"),
+        }
+    }
+}
+
+impl ToRow for NexumTestIntrospectFixture {
+    fn table() -> &'static str {
+        "public._nexum_test_introspect_fixture"
+    }
+
+    fn columns() -> &'static [&'static str] {
+        &["id", "\"a b\"", "a_b", "\"A_B\"", "type", "tags"]
+    }
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![&self.id, &self.a_b, &self.a_b_2, &self.a_b_3, &self.r#type, &self.tags]
+    }
+}
@@ -0,0 +1,294 @@
+//! Support for the pgvector extension: the Vector (vector) and HalfVector (halfvec) types
+//! can be passed as parameters and read from rows like any other type, i.e. row.get::<_, Vector>("embedding"),
+//! plus helpers for nearest neighbour queries and for creating HNSW or IVFFlat indexes
+
+use std::{error::Error, marker::Sync};
+use bytes::{Buf, BufMut, BytesMut};
+use half::f16;
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use crate::core::{GenericError, SimpleError};
+use super::{get_vec, query::is_identifier, Client, Row};
+
+
+/// An embedding stored in a vector column, with 4 bytes per dimension
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector(pub Vec<f32>);
+
+/// An embedding stored in a halfvec column, with 2 bytes per dimension
+#[derive(Debug, Clone, PartialEq)]
+pub struct HalfVector(pub Vec<f16>);
+
+impl From<Vec<f32>> for Vector {
+    fn from(v: Vec<f32>) -> Self {
+        Vector(v)
+    }
+}
+
+impl From<Vec<f32>> for HalfVector {
+    fn from(v: Vec<f32>) -> Self {
+        HalfVector(v.into_iter().map(f16::from_f32).collect())
+    }
+}
+
+impl HalfVector {
+    /// widen to f32s
+    pub fn to_f32(&self) -> Vec<f32> {
+        self.0.iter().map(|x| x.to_f32()).collect()
+    }
+}
+
+// both types are sent as a 2 byte dimension count, 2 unused bytes, then each element big-endian
+
+/// read the header, returning the dimension count once the buffer is known to be long enough
+fn read_header(mut raw: &[u8], element_size: usize) -> Result<(usize, &[u8]), Box<dyn Error + Sync + Send>> {
+    if raw.len() < 4 {
+        return Err(SimpleError::from_str("vector is missing its header").into())
+    }
+    let dim = raw.get_u16() as usize;
+    let _unused = raw.get_u16();
+    if raw.len() != dim * element_size {
+        return Err(SimpleError{message: format!("expected {} bytes for a vector of {} dimensions but got {}", dim * element_size, dim, raw.len())}.into())
+    }
+    Ok((dim, raw))
+}
+
+impl ToSql for Vector {
+    fn to_sql(&self, _ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_u16(u16::try_from(self.0.len())?);
+        out.put_u16(0);
+        for x in &self.0 {
+            out.put_f32(*x);
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "vector"
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for Vector {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let (dim, mut raw) = read_header(raw, 4)?;
+        Ok(Vector((0..dim).map(|_| raw.get_f32()).collect()))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "vector"
+    }
+}
+
+impl ToSql for HalfVector {
+    fn to_sql(&self, _ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_u16(u16::try_from(self.0.len())?);
+        out.put_u16(0);
+        for x in &self.0 {
+            out.put_u16(x.to_bits());
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "halfvec"
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for HalfVector {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let (dim, mut raw) = read_header(raw, 2)?;
+        Ok(HalfVector((0..dim).map(|_| f16::from_bits(raw.get_u16())).collect()))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "halfvec"
+    }
+}
+
+
+/// How the distance between two vectors is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distance {
+    /// Euclidean distance, <->
+    L2,
+    /// cosine distance (1 - cosine similarity), <=>
+    Cosine,
+    /// the negative inner product, <#> (negated so that smaller is closer, like the others)
+    InnerProduct,
+}
+
+impl Distance {
+    fn operator(&self) -> &'static str {
+        match self {
+            Distance::L2 => "<->",
+            Distance::Cosine => "<=>",
+            Distance::InnerProduct => "<#>",
+        }
+    }
+
+    fn ops_suffix(&self) -> &'static str {
+        match self {
+            Distance::L2 => "l2_ops",
+            Distance::Cosine => "cosine_ops",
+            Distance::InnerProduct => "ip_ops",
+        }
+    }
+}
+
+/// The kinds of approximate nearest neighbour index pgvector supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMethod {
+    /// better recall and query speed, slower to build. pgvector defaults to m = 16, ef_construction = 64
+    Hnsw{m: i32, ef_construction: i32},
+    /// quick to build, but should be created after the table has data. A good start is rows / 1000 lists
+    IvfFlat{lists: i32},
+}
+
+
+/// ensure every name is a plain identifier
+fn check_identifiers(names: &[&str]) -> Result<(), GenericError> {
+    match names.iter().find(|n| !is_identifier(n)) {
+        Some(bad) => Err(SimpleError{message: format!("\"{}\" is not a valid identifier", bad)}.into()),
+        None => Ok(()),
+    }
+}
+
+/// Builds a nearest neighbour query. The rows it returns have the selected columns plus a "distance" column, nearest first
+pub struct NearestQuery {
+    table: String,
+    vector_column: String,
+    distance: Distance,
+    columns: Vec<String>,
+    limit: i64,
+}
+
+impl NearestQuery {
+
+    /// Search a vector (or halfvec) column of a table, measuring distance in the given way
+    pub fn new(table: &str, vector_column: &str, distance: Distance) -> Self {
+        NearestQuery {
+            table: table.to_string(),
+            vector_column: vector_column.to_string(),
+            distance,
+            columns: Vec::new(),
+            limit: 10,
+        }
+    }
+
+    /// The columns to return
+    pub fn select(mut self, columns: &[&str]) -> Self {
+        self.columns = columns.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// Return this many neighbours
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// The SQL, which takes the vector to search near as $1
+    pub fn sql(&self) -> Result<String, GenericError> {
+        check_identifiers(&[&self.table, &self.vector_column])?;
+        check_identifiers(&self.columns.iter().map(|c| c.as_str()).collect::<Vec<&str>>())?;
+        let op = self.distance.operator();
+        let mut select = self.columns.clone();
+        select.push(format!("{} {} $1 AS distance", self.vector_column, op));
+        Ok(format!("SELECT {} FROM {} ORDER BY {} {} $1 LIMIT {}", select.join(", "), self.table, self.vector_column, op, self.limit))
+    }
+}
+
+/// Return the rows nearest to a vector (a Vector or HalfVector, matching the column), mapped by rowfunc
+pub async fn nearest<'a, T, V>(client: &'a Client, nearest: &NearestQuery, query: &V, rowfunc: &'a (dyn Fn(&Row) -> T + Sync)) -> Result<Vec<T>, GenericError>
+where
+    V: ToSql + Sync,
+{
+    let sql = nearest.sql()?;
+    get_vec(client, &sql, rowfunc, &[query]).await
+}
+
+/// SQL creating an approximate nearest neighbour index on a vector (or halfvec if half_precision) column
+pub fn create_index_sql(table: &str, vector_column: &str, distance: Distance, method: IndexMethod, half_precision: bool) -> Result<String, GenericError> {
+    check_identifiers(&[table, vector_column])?;
+    let opclass = format!("{}_{}", if half_precision { "halfvec" } else { "vector" }, distance.ops_suffix());
+    let (using, with) = match method {
+        IndexMethod::Hnsw{m, ef_construction} => ("hnsw", format!("m = {}, ef_construction = {}", m, ef_construction)),
+        IndexMethod::IvfFlat{lists} => ("ivfflat", format!("lists = {}", lists)),
+    };
+    let name = format!("{}_{}_{}_{}_idx", table.replace('.', "_"), vector_column, using, distance.ops_suffix());
+    Ok(format!("CREATE INDEX IF NOT EXISTS {} ON {} USING {} ({} {}) WITH ({})", name, table, using, vector_column, opclass, with))
+}
+
+/// Create an approximate nearest neighbour index if it doesn't exist yet
+pub async fn create_index(client: &Client, table: &str, vector_column: &str, distance: Distance, method: IndexMethod, half_precision: bool) -> Result<(), GenericError> {
+    let sql = create_index_sql(table, vector_column, distance, method, half_precision)?;
+    client.batch_execute(&sql).await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use tokio_postgres::types::Kind;
+    use crate::postgres::pool_no_tls_from_env;
+
+    #[test]
+    fn encode_decode() {
+        let vector_type = Type::new("vector".to_string(), 0, Kind::Simple, "public".to_string());
+        let v = Vector(vec![1.0, -2.5, 3.25]);
+        let mut buf = BytesMut::new();
+        v.to_sql_checked(&vector_type, &mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 3 * 4);
+        assert_eq!(Vector::from_sql(&vector_type, &buf).unwrap(), v);
+        assert!(Vector::from_sql(&vector_type, &buf[..7]).is_err());
+        let halfvec_type = Type::new("halfvec".to_string(), 0, Kind::Simple, "public".to_string());
+        let h = HalfVector::from(vec![0.5, -1.0]);
+        let mut buf = BytesMut::new();
+        h.to_sql_checked(&halfvec_type, &mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 2 * 2);
+        assert_eq!(HalfVector::from_sql(&halfvec_type, &buf).unwrap().to_f32(), vec![0.5, -1.0]);
+        // a Vector can't be written to a halfvec column
+        assert!(v.to_sql_checked(&halfvec_type, &mut buf).is_err());
+    }
+
+    #[test]
+    fn generate_sql() {
+        let sql = NearestQuery::new("items", "embedding", Distance::Cosine).select(&["id", "title"]).limit(5).sql().unwrap();
+        assert_eq!(sql, "SELECT id, title, embedding <=> $1 AS distance FROM items ORDER BY embedding <=> $1 LIMIT 5");
+        let sql = create_index_sql("items", "embedding", Distance::L2, IndexMethod::Hnsw{m: 16, ef_construction: 64}, false).unwrap();
+        assert_eq!(sql, "CREATE INDEX IF NOT EXISTS items_embedding_hnsw_l2_ops_idx ON items USING hnsw (embedding vector_l2_ops) WITH (m = 16, ef_construction = 64)");
+        let sql = create_index_sql("items", "embedding", Distance::InnerProduct, IndexMethod::IvfFlat{lists: 100}, true).unwrap();
+        assert_eq!(sql, "CREATE INDEX IF NOT EXISTS items_embedding_ivfflat_ip_ops_idx ON items USING ivfflat (embedding halfvec_ip_ops) WITH (lists = 100)");
+        assert!(NearestQuery::new("items; --", "embedding", Distance::L2).sql().is_err());
+    }
+
+    #[test]
+    #[ignore = "needs the pgvector extension installed on the server, run with --ignored"]
+    fn nearest_neighbours() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_env().await.unwrap();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("CREATE EXTENSION IF NOT EXISTS vector").await.unwrap();
+            client.batch_execute("DROP TABLE IF EXISTS _nexum_test_vectors;
+                CREATE TABLE _nexum_test_vectors (id INT PRIMARY KEY, embedding vector(3), half halfvec(3))").await.unwrap();
+            for (id, v) in [(1, vec![1.0, 0.0, 0.0]), (2, vec![0.0, 1.0, 0.0]), (3, vec![0.9, 0.1, 0.0])] {
+                client.execute("INSERT INTO _nexum_test_vectors (id, embedding, half) VALUES ($1, $2, $3)",
+                    &[&id, &Vector::from(v.clone()), &HalfVector::from(v)]).await.unwrap();
+            }
+            create_index(&client, "_nexum_test_vectors", "embedding", Distance::Cosine, IndexMethod::Hnsw{m: 16, ef_construction: 64}, false).await.unwrap();
+            let query = Vector(vec![1.0, 0.05, 0.0]);
+            let knn = NearestQuery::new("_nexum_test_vectors", "embedding", Distance::Cosine).select(&["id"]).limit(2);
+            let ids = nearest(&client, &knn, &query, &|row| row.get::<_, i32>("id")).await.unwrap();
+            assert_eq!(ids, vec![1, 3]);
+            let rows = get_vec(&client, "SELECT embedding, half FROM _nexum_test_vectors WHERE id = 2", &|row| (row.get::<_, Vector>(0), row.get::<_, HalfVector>(1)), &[]).await.unwrap();
+            assert_eq!(rows[0].0, Vector(vec![0.0, 1.0, 0.0]));
+            assert_eq!(rows[0].1.to_f32(), vec![0.0, 1.0, 0.0]);
+        })
+    }
+}
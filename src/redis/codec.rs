@@ -0,0 +1,313 @@
+//! Serialization formats for rediserde values, with optional compression, i.e.
+//! ```ignore
+//! rediserde::set_with(&rpool, "user:42", &user, Zstd::new(MessagePack)).await?;
+//! let user: Option<User> = rediserde::get(&rpool, "user:42").await?;
+//! ```
+//! Every value but plain JSON starts with a header byte naming its format and compression, so any codec
+//! (and every rediserde read) decodes values written by any other, and a cache can switch formats without being flushed.
+//! Plain JSON is written without a header, exactly as rediserde always has, so values stay readable by older services.
+//! The header byte has the high bit set, which no JSON text can start with.
+//! Bincode isn't self-describing, so it can't decode serde_json::Value, untagged enums or skipped fields.
+//! Each codec and compressor besides JSON is behind a cargo feature of the same name (messagepack, bincode, cbor, zstd, lz4),
+//! all on by default. Reading a value whose format's feature is off is an error
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json;
+use crate::core::{GenericError, SimpleError};
+
+const HEADER: u8 = 0x80;
+const FORMAT_MASK: u8 = 0x0f;
+const COMPRESSION_SHIFT: u8 = 4;
+const COMPRESSION_MASK: u8 = 0x07;
+
+pub const FORMAT_JSON: u8 = 0;
+pub const FORMAT_MESSAGEPACK: u8 = 1;
+pub const FORMAT_BINCODE: u8 = 2;
+pub const FORMAT_CBOR: u8 = 3;
+
+pub const COMPRESSION_NONE: u8 = 0;
+pub const COMPRESSION_ZSTD: u8 = 1;
+pub const COMPRESSION_LZ4: u8 = 2;
+
+/// zstd's own default, a good balance for small cache values
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+
+/// A way of turning values into bytes and back
+pub trait Codec: Send + Sync {
+
+    /// The format's id in the header byte, one of the FORMAT_ constants
+    fn format(&self) -> u8;
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError>;
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError>;
+
+    /// Compress serialized bytes, returning the compression's id for the header. No compression by default
+    fn compress(&self, bytes: Vec<u8>) -> Result<(u8, Vec<u8>), GenericError> {
+        Ok((COMPRESSION_NONE, bytes))
+    }
+
+    /// Serialize and compress a value, behind a header byte unless it is plain JSON
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        let (compression, body) = self.compress(self.serialize(value)?)?;
+        if self.format() == FORMAT_JSON && compression == COMPRESSION_NONE {
+            return Ok(body)
+        }
+        let mut bytes = Vec::with_capacity(body.len() + 1);
+        bytes.push(HEADER | compression << COMPRESSION_SHIFT | self.format());
+        bytes.extend(body);
+        Ok(bytes)
+    }
+
+    /// Decode a value written by any codec
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        decode(bytes)
+    }
+}
+
+/// A borrowed codec, so one that is kept in a struct can be lent to the rediserde _with functions
+impl<C: Codec> Codec for &C {
+    fn format(&self) -> u8 {
+        (*self).format()
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        (*self).serialize(value)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        (*self).deserialize(bytes)
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Result<(u8, Vec<u8>), GenericError> {
+        (*self).compress(bytes)
+    }
+}
+
+/// Decode a value written by any codec, going by its header byte, or plain JSON if it has none
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, GenericError> {
+    let header = match bytes.first() {
+        Some(b) if b & HEADER != 0 => *b,
+        _ => return Json.deserialize(bytes),
+    };
+    let body = match header >> COMPRESSION_SHIFT & COMPRESSION_MASK {
+        COMPRESSION_NONE => bytes[1..].to_vec(),
+        #[cfg(feature = "zstd")]
+        COMPRESSION_ZSTD => zstd::stream::decode_all(&bytes[1..])?,
+        #[cfg(feature = "lz4")]
+        COMPRESSION_LZ4 => lz4_flex::decompress_size_prepended(&bytes[1..])?,
+        c => return Err(SimpleError{message: format!("unknown or disabled compression {} in header byte {:#04x}", c, header)}.into()),
+    };
+    match header & FORMAT_MASK {
+        FORMAT_JSON => Json.deserialize(&body),
+        #[cfg(feature = "messagepack")]
+        FORMAT_MESSAGEPACK => MessagePack.deserialize(&body),
+        #[cfg(feature = "bincode")]
+        FORMAT_BINCODE => Bincode.deserialize(&body),
+        #[cfg(feature = "cbor")]
+        FORMAT_CBOR => Cbor.deserialize(&body),
+        f => Err(SimpleError{message: format!("unknown or disabled format {} in header byte {:#04x}", f, header)}.into()),
+    }
+}
+
+
+/// serde_json, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn format(&self) -> u8 {
+        FORMAT_JSON
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// MessagePack with field names, so structs can gain and lose fields like they can with JSON
+#[cfg(feature = "messagepack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "messagepack")]
+impl Codec for MessagePack {
+    fn format(&self) -> u8 {
+        FORMAT_MESSAGEPACK
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// bincode, the smallest and fastest, but fields are positional so every reader needs the same struct definition
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn format(&self) -> u8 {
+        FORMAT_BINCODE
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// CBOR
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn format(&self) -> u8 {
+        FORMAT_CBOR
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+
+/// Another codec's output compressed with zstd, which shrinks JSON several times over
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct Zstd<C> {
+    codec: C,
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl<C: Codec> Zstd<C> {
+    pub fn new(codec: C) -> Self {
+        Zstd{codec, level: DEFAULT_ZSTD_LEVEL}
+    }
+
+    /// Compression level from 1 (fastest) to 22 (smallest)
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<C: Codec> Codec for Zstd<C> {
+    fn format(&self) -> u8 {
+        self.codec.format()
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        self.codec.serialize(value)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        self.codec.deserialize(bytes)
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Result<(u8, Vec<u8>), GenericError> {
+        Ok((COMPRESSION_ZSTD, zstd::bulk::compress(&bytes, self.level)?))
+    }
+}
+
+/// Another codec's output compressed with LZ4, which compresses less than zstd but is faster
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy)]
+pub struct Lz4<C>(pub C);
+
+#[cfg(feature = "lz4")]
+impl<C: Codec> Codec for Lz4<C> {
+    fn format(&self) -> u8 {
+        self.0.format()
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, GenericError> {
+        self.0.serialize(value)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, GenericError> {
+        self.0.deserialize(bytes)
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Result<(u8, Vec<u8>), GenericError> {
+        Ok((COMPRESSION_LZ4, lz4_flex::compress_prepend_size(&bytes)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Doc {
+        id: i64,
+        title: String,
+        tags: Vec<String>,
+        score: Option<f64>,
+    }
+
+    fn doc() -> Doc {
+        Doc{id: 42, title: "a title repeated, a title repeated, a title repeated".to_string(), tags: vec!["x".to_string(), "y".to_string()], score: None}
+    }
+
+    fn round_trip<C: Codec>(codec: C) -> Vec<u8> {
+        let bytes = codec.encode(&doc()).unwrap();
+        assert_eq!(codec.decode::<Doc>(&bytes).unwrap(), doc());
+        // any codec reads what another wrote
+        assert_eq!(Json.decode::<Doc>(&bytes).unwrap(), doc());
+        bytes
+    }
+
+    #[test]
+    #[cfg(all(feature = "messagepack", feature = "bincode", feature = "cbor", feature = "zstd", feature = "lz4"))]
+    fn encode_decode() {
+        // plain JSON has no header, so existing values and readers keep working
+        let json = round_trip(Json);
+        assert_eq!(json, serde_json::to_vec(&doc()).unwrap());
+        assert_eq!(round_trip(MessagePack)[0], HEADER | FORMAT_MESSAGEPACK);
+        assert_eq!(round_trip(Bincode)[0], HEADER | FORMAT_BINCODE);
+        assert_eq!(round_trip(Cbor)[0], HEADER | FORMAT_CBOR);
+        let zstd = round_trip(Zstd::new(Json).level(19));
+        assert_eq!(zstd[0], HEADER | COMPRESSION_ZSTD << COMPRESSION_SHIFT | FORMAT_JSON);
+        assert!(zstd.len() < json.len());
+        assert_eq!(round_trip(Lz4(MessagePack))[0], HEADER | COMPRESSION_LZ4 << COMPRESSION_SHIFT | FORMAT_MESSAGEPACK);
+        round_trip(Zstd::new(Bincode));
+        round_trip(Lz4(Cbor));
+    }
+
+    #[test]
+    fn bad_bytes() {
+        assert!(decode::<Doc>(&[HEADER | 0x0f, 1, 2]).is_err());
+        assert!(decode::<Doc>(&[HEADER | 0x70]).is_err());
+        assert!(decode::<Doc>(&[HEADER | COMPRESSION_ZSTD << COMPRESSION_SHIFT, 1, 2, 3]).is_err());
+        assert!(decode::<Doc>(b"{\"id\": 1}").is_err());
+        let mut bytes = Json.encode(&doc()).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        assert!(decode::<Doc>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,609 @@
+//! A reliable work queue in Redis, to replace rediserde::sadd_str/spop_str, which lose a job if the worker
+//! that popped it crashes, i.e.
+//! ```ignore
+//! let queue = Arc::new(Queue::new(&rpool, "emails").visibility_timeout(Duration::from_secs(60)));
+//! queue.reaper(Duration::from_secs(10));
+//! queue.enqueue(&email, 10, Duration::ZERO).await?;
+//!
+//! for job in queue.dequeue().await? {
+//!     send(&job.deserialize::<Email>()?).await?;
+//!     queue.ack(&job.id).await?;
+//! }
+//! ```
+//! Dequeuing LMOVEs a job's id from a ready list into the processing list and gives it a visibility deadline.
+//! A job that isn't acked by its deadline is requeued by the reaper, and once it has been delivered
+//! max_attempts times it is dead-lettered instead. Delivery is at least once, so jobs should be idempotent.
+//! Requeueing, nacking and promoting delayed jobs are Lua scripts that only push a job they have just removed
+//! from where it was, so racing workers and reapers can't queue a job twice.
+//!
+//! Each priority has its own ready list and higher priorities are dequeued first.
+//! Delayed jobs wait in a sorted set scored by when they are due, and are moved to their ready list by dequeue.
+//! A job pushed with a dedup_id is only enqueued once within the dedup window.
+//!
+//! Queue has the same push/poll_strings/poll methods as sqs::Messenger and postgres::queue::Queue,
+//! so a worker written against one can be pointed at the others.
+//! Deadlines are taken from the workers' clocks, which should be kept in sync
+
+use std::{sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use mobc_redis::redis::{self, AsyncCommands};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json;
+use crate::core::{GenericError, SimpleError};
+use super::RedisPool;
+
+const DEFAULT_VISIBILITY_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_ATTEMPTS: u64 = 5;
+const DEFAULT_BATCH_SIZE: usize = 10;
+/// the same as a FIFO SQS queue's
+const DEFAULT_DEDUP_WINDOW_SECONDS: u64 = 300;
+
+/// Store a job ARGV[1] under the next id from KEYS[1] in the jobs hash KEYS[2], record its priority ARGV[2] in KEYS[3],
+/// then push it onto the ready list KEYS[4], or delay it in KEYS[4] until ARGV[3] if that isn't empty, returning the id.
+/// With a dedup key KEYS[5] the id of a job enqueued within the last ARGV[4] ms is returned instead, without storing anything
+const ENQUEUE_SCRIPT: &str = r"
+if #KEYS == 5 then
+    local original = redis.call('GET', KEYS[5])
+    if original then
+        return original
+    end
+end
+local id = tostring(redis.call('INCR', KEYS[1]))
+redis.call('HSET', KEYS[2], id, ARGV[1])
+redis.call('ZADD', KEYS[3], ARGV[2], ARGV[2])
+if ARGV[3] == '' then
+    redis.call('LPUSH', KEYS[4], id)
+else
+    redis.call('ZADD', KEYS[4], ARGV[3], id)
+end
+if #KEYS == 5 then
+    redis.call('SET', KEYS[5], id, 'PX', ARGV[4])
+end
+return id
+";
+
+/// Push job ARGV[1] onto the ready list KEYS[2] if it was still in the delayed set KEYS[1]
+const PROMOTE_SCRIPT: &str = r"
+if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+redis.call('LPUSH', KEYS[2], ARGV[1])
+return 1
+";
+
+/// Give every job in the processing list KEYS[1] that has no deadline in KEYS[2] the deadline ARGV[1]
+const DEADLINES_SCRIPT: &str = r"
+local ids = redis.call('LRANGE', KEYS[1], 0, -1)
+for _, id in ipairs(ids) do
+    redis.call('ZADD', KEYS[2], 'NX', ARGV[1], id)
+end
+return #ids
+";
+
+/// Requeue job ARGV[1] onto the list KEYS[3] if its deadline in KEYS[1] is still no later than ARGV[2]
+/// and it is still in the processing list KEYS[2]
+const REQUEUE_SCRIPT: &str = r"
+local deadline = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not deadline or tonumber(deadline) > tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+if redis.call('LREM', KEYS[2], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call('RPUSH', KEYS[3], ARGV[1])
+return 1
+";
+
+/// If job ARGV[1] is in the processing list KEYS[1], take it and its deadline in KEYS[2] out, then delay it
+/// in KEYS[3] until ARGV[2], or dead-letter it onto the list KEYS[4] if ARGV[2] is empty
+const NACK_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call('ZREM', KEYS[2], ARGV[1])
+if ARGV[2] == '' then
+    redis.call('RPUSH', KEYS[4], ARGV[1])
+else
+    redis.call('ZADD', KEYS[3], ARGV[2], ARGV[1])
+end
+return 1
+";
+
+
+/// A job dequeued from a queue. Ack it once it has been processed
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub group_id: Option<String>,
+    pub payload: String,
+    pub priority: i32,
+    /// how many times this job has been delivered, including this time
+    pub attempts: u64,
+}
+
+impl Job {
+    /// deserialize the payload
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, GenericError> {
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+/// What is kept of a job in the queue's jobs hash until it is acked
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    group_id: Option<String>,
+    payload: String,
+    priority: i32,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+
+/// A named reliable queue, with a visibility timeout, a limit on attempts and a dedup window
+pub struct Queue {
+    pool: RedisPool,
+    name: String,
+    visibility_timeout: Duration,
+    max_attempts: u64,
+    batch_size: usize,
+    dedup_window: Duration,
+}
+
+impl Queue {
+
+    /// Instantiate a queue. Its keys all start with name
+    pub fn new(pool: &RedisPool, name: &str) -> Self {
+        Queue {
+            pool: pool.clone(),
+            name: name.to_string(),
+            visibility_timeout: Duration::from_secs(DEFAULT_VISIBILITY_TIMEOUT_SECONDS),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            batch_size: DEFAULT_BATCH_SIZE,
+            dedup_window: Duration::from_secs(DEFAULT_DEDUP_WINDOW_SECONDS),
+        }
+    }
+
+    /// How long a dequeued job stays hidden from other workers before it is requeued
+    pub fn visibility_timeout(mut self, visibility_timeout: Duration) -> Self {
+        self.visibility_timeout = visibility_timeout;
+        self
+    }
+
+    /// How many times a job is delivered before it is dead-lettered
+    pub fn max_attempts(mut self, max_attempts: u64) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The most jobs a single dequeue returns
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long a dedup_id keeps a job with the same dedup_id from being enqueued again
+    pub fn dedup_window(mut self, dedup_window: Duration) -> Self {
+        self.dedup_window = dedup_window;
+        self
+    }
+
+    fn key(&self, suffix: &str) -> String {
+        format!("{}:{}", self.name, suffix)
+    }
+
+    fn ready_key(&self, priority: i32) -> String {
+        format!("{}:ready:{}", self.name, priority)
+    }
+
+    /// Add a job that becomes available after delay. Higher priority jobs are dequeued first
+    pub async fn enqueue<T: Serialize>(&self, msg: &T, priority: i32, delay: Duration) -> Result<String, GenericError> {
+        let body = serde_json::to_string(msg)?;
+        self.enqueue_str(&body, None, priority, delay, None).await
+    }
+
+    /// Add a job body as is. If a job with the same dedup_id was enqueued within the dedup window,
+    /// nothing is added and that job's id is returned. The check and the enqueue are one script,
+    /// so a dedup_id is only ever recorded along with the job it belongs to
+    pub async fn enqueue_str(&self, body: &str, group_id: Option<&str>, priority: i32, delay: Duration, dedup_id: Option<&str>) -> Result<String, GenericError> {
+        let stored = serde_json::to_string(&Stored{group_id: group_id.map(|g| g.to_string()), payload: body.to_string(), priority})?;
+        let enqueue = redis::Script::new(ENQUEUE_SCRIPT);
+        let mut script = enqueue.prepare_invoke();
+        script.key(self.key("seq")).key(self.key("jobs")).key(self.key("priorities"));
+        let due = if delay.is_zero() {
+            script.key(self.ready_key(priority));
+            String::new()
+        } else {
+            script.key(self.key("delayed"));
+            (now_ms() + delay.as_millis() as u64).to_string()
+        };
+        if let Some(dedup_id) = dedup_id {
+            script.key(self.key(&format!("dedup:{}", dedup_id)));
+        }
+        script.arg(stored).arg(priority).arg(due).arg(self.dedup_window.as_millis() as u64);
+        let mut rconn = self.pool.get().await?;
+        let id: String = script.invoke_async(&mut *rconn).await?;
+        Ok(id)
+    }
+
+    /// Claim up to batch_size available jobs, highest priority first then oldest first,
+    /// after moving due delayed jobs to their ready lists and requeueing abandoned ones
+    pub async fn dequeue(&self) -> Result<Vec<Job>, GenericError> {
+        self.promote().await?;
+        self.reap().await?;
+        let mut rconn = self.pool.get().await?;
+        let priorities: Vec<i32> = rconn.zrevrange(self.key("priorities"), 0, -1).await?;
+        let processing = self.key("processing");
+        let mut jobs = Vec::new();
+        for priority in priorities {
+            while jobs.len() < self.batch_size {
+                let id: Option<String> = redis::cmd("LMOVE").arg(self.ready_key(priority)).arg(&processing)
+                    .arg("RIGHT").arg("LEFT").query_async(&mut *rconn).await?;
+                let id = match id {
+                    Some(id) => id,
+                    None => break,
+                };
+                let deadline = now_ms() + self.visibility_timeout.as_millis() as u64;
+                let (stored, attempts): (Option<String>, u64) = redis::pipe().atomic()
+                    .zadd(self.key("deadlines"), &id, deadline).ignore()
+                    .hget(self.key("jobs"), &id)
+                    .hincr(self.key("attempts"), &id, 1)
+                    .query_async(&mut *rconn).await?;
+                match stored {
+                    Some(stored) => {
+                        let stored: Stored = serde_json::from_str(&stored)?;
+                        jobs.push(Job{id, group_id: stored.group_id, payload: stored.payload, priority: stored.priority, attempts});
+                    },
+                    // acked while it was being requeued, so there is nothing left to deliver
+                    None => { self.forget(&id).await?; },
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Move delayed jobs that are due to their ready lists. Workers promoting at once can find the same jobs due,
+    /// but only the one that takes a job out of the delayed set pushes it
+    async fn promote(&self) -> Result<usize, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let due: Vec<String> = rconn.zrangebyscore_limit(self.key("delayed"), 0, now_ms(), 0, self.batch_size as isize).await?;
+        let mut promoted = 0;
+        for id in &due {
+            let stored: Option<String> = rconn.hget(self.key("jobs"), id).await?;
+            let stored: Stored = match stored {
+                Some(stored) => serde_json::from_str(&stored)?,
+                // acked while it was delayed, so there is nothing left to deliver
+                None => { let _ : () = rconn.zrem(self.key("delayed"), id).await?; continue },
+            };
+            let pushed: usize = redis::Script::new(PROMOTE_SCRIPT).key(self.key("delayed")).key(self.ready_key(stored.priority))
+                .arg(id).invoke_async(&mut *rconn).await?;
+            promoted += pushed;
+        }
+        Ok(promoted)
+    }
+
+    /// Requeue jobs whose visibility deadline has passed, or dead-letter them if they have run out of attempts,
+    /// returning how many were requeued or dead-lettered. dequeue reaps too, but a reaper keeps jobs moving
+    /// while there are no workers dequeuing
+    pub async fn reap(&self) -> Result<usize, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let now = now_ms();
+        // a worker that crashed between LMOVE and setting the deadline left a job without one, so give it one.
+        // This is a script so it only sees jobs that are in the processing list at that moment
+        let _ : usize = redis::Script::new(DEADLINES_SCRIPT).key(self.key("processing")).key(self.key("deadlines"))
+            .arg(now + self.visibility_timeout.as_millis() as u64).invoke_async(&mut *rconn).await?;
+        let expired: Vec<String> = rconn.zrangebyscore_limit(self.key("deadlines"), 0, now, 0, self.batch_size as isize).await?;
+        let mut reaped = 0;
+        for id in expired {
+            let (stored, attempts): (Option<String>, Option<u64>) = redis::pipe()
+                .hget(self.key("jobs"), &id)
+                .hget(self.key("attempts"), &id)
+                .query_async(&mut *rconn).await?;
+            let stored: Stored = match stored {
+                Some(stored) => serde_json::from_str(&stored)?,
+                None => { self.forget(&id).await?; continue },
+            };
+            let attempts = attempts.unwrap_or_default();
+            let dead = attempts >= self.max_attempts;
+            // to the front of the line, since it has waited already
+            let target = if dead { self.key("dead") } else { self.ready_key(stored.priority) };
+            // only whoever takes the job out of the processing list pushes it, and only if its deadline hasn't
+            // been pushed back since, i.e. because another reaper requeued it and a worker dequeued it again
+            let requeued: usize = redis::Script::new(REQUEUE_SCRIPT).key(self.key("deadlines")).key(self.key("processing")).key(target)
+                .arg(&id).arg(now).invoke_async(&mut *rconn).await?;
+            if requeued == 0 {
+                continue
+            }
+            if dead {
+                println!("ERROR! queue {} dead-lettered job {} after {} attempts", self.name, id, attempts);
+            }
+            reaped += 1;
+        }
+        Ok(reaped)
+    }
+
+    /// Reap every interval until the queue is dropped
+    pub fn reaper(self: &Arc<Self>, interval: Duration) {
+        let queue = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let queue = match queue.upgrade() {
+                    Some(queue) => queue,
+                    None => return,
+                };
+                if let Err(e) = queue.reap().await {
+                    println!("ERROR! queue {} could not reap: {}", queue.name, e);
+                }
+            }
+        });
+    }
+
+    /// Remove every trace of a job from the processing list, deadlines and jobs hash
+    async fn forget(&self, id: &str) -> Result<bool, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let (removed,): (usize,) = redis::pipe().atomic()
+            .lrem(self.key("processing"), 1, id)
+            .zrem(self.key("deadlines"), id).ignore()
+            .hdel(self.key("jobs"), id).ignore()
+            .hdel(self.key("attempts"), id).ignore()
+            .query_async(&mut *rconn).await?;
+        Ok(removed > 0)
+    }
+
+    /// Remove a job that has been processed, returning false if it wasn't being processed,
+    /// i.e. its visibility deadline passed and it was requeued
+    pub async fn ack(&self, id: &str) -> Result<bool, GenericError> {
+        self.forget(id).await
+    }
+
+    /// Give a job back to be retried after retry_delay, or dead-letter it if it has run out of attempts.
+    /// Returns false, leaving the job alone, if it wasn't being processed, i.e. it was acked or requeued already
+    pub async fn nack(&self, id: &str, retry_delay: Duration) -> Result<bool, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let attempts: Option<u64> = rconn.hget(self.key("attempts"), id).await?;
+        let due = if attempts.unwrap_or_default() >= self.max_attempts {
+            String::new()
+        } else {
+            (now_ms() + retry_delay.as_millis() as u64).to_string()
+        };
+        let released: usize = redis::Script::new(NACK_SCRIPT)
+            .key(self.key("processing")).key(self.key("deadlines")).key(self.key("delayed")).key(self.key("dead"))
+            .arg(id).arg(due).invoke_async(&mut *rconn).await?;
+        Ok(released == 1)
+    }
+
+    /// List the jobs that have been dead-lettered, oldest first
+    pub async fn dead_letters(&self) -> Result<Vec<Job>, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let ids: Vec<String> = rconn.lrange(self.key("dead"), 0, -1).await?;
+        let mut jobs = Vec::new();
+        for id in ids {
+            let (stored, attempts): (Option<String>, Option<u64>) = redis::pipe()
+                .hget(self.key("jobs"), &id)
+                .hget(self.key("attempts"), &id)
+                .query_async(&mut *rconn).await?;
+            if let Some(stored) = stored {
+                let stored: Stored = serde_json::from_str(&stored)?;
+                jobs.push(Job{id, group_id: stored.group_id, payload: stored.payload, priority: stored.priority, attempts: attempts.unwrap_or_default()});
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Put a dead-lettered job back on the queue with its attempts reset
+    pub async fn retry_dead(&self, id: &str) -> Result<(), GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let stored: Option<String> = rconn.hget(self.key("jobs"), id).await?;
+        let stored: Stored = match stored {
+            Some(stored) => serde_json::from_str(&stored)?,
+            None => return Err(SimpleError{message: format!("queue {} has no job {}", self.name, id)}.into()),
+        };
+        let removed: usize = rconn.lrem(self.key("dead"), 1, id).await?;
+        if removed == 0 {
+            return Err(SimpleError{message: format!("job {} in queue {} is not dead-lettered", id, self.name)}.into())
+        }
+        let _ : () = redis::pipe().atomic()
+            .hdel(self.key("attempts"), id).ignore()
+            .lpush(self.ready_key(stored.priority), id).ignore()
+            .query_async(&mut *rconn).await?;
+        Ok(())
+    }
+
+    /// How many jobs are waiting, delayed or in flight, not counting dead letters
+    pub async fn count(&self) -> Result<usize, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let priorities: Vec<i32> = rconn.zrevrange(self.key("priorities"), 0, -1).await?;
+        let mut count = 0;
+        for priority in priorities {
+            let n: usize = rconn.llen(self.ready_key(priority)).await?;
+            count += n;
+        }
+        let processing: usize = rconn.llen(self.key("processing")).await?;
+        let delayed: usize = rconn.zcard(self.key("delayed")).await?;
+        Ok(count + processing + delayed)
+    }
+
+    /// publish a message (could be a string or serializable struct) to the queue with a given group_id
+    /// This mirrors sqs::Messenger::push, returning the job id
+    pub async fn push<T: Serialize>(&self, msg: &T, group_id: &str) -> Result<String, GenericError> {
+        let body = serde_json::to_string(msg)?;
+        self.push_str(&body, group_id, None).await
+    }
+
+    /// publish a message body as is. Messages sent with the same dedup_id within the dedup window
+    /// are only enqueued once, like on a FIFO SQS queue
+    pub async fn push_str(&self, body: &str, group_id: &str, dedup_id: Option<&str>) -> Result<String, GenericError> {
+        self.enqueue_str(body, Some(group_id), 0, Duration::ZERO, dedup_id).await
+    }
+
+    /// Return the payloads of jobs as strings.
+    /// If delete_on_receipt is false the jobs are redelivered once the visibility timeout expires, just like SQS
+    pub async fn poll_strings(&self, delete_on_receipt: bool) -> Result<Vec<String>, GenericError> {
+        let jobs = self.dequeue().await?;
+        if delete_on_receipt {
+            for job in &jobs {
+                self.ack(&job.id).await?;
+            }
+        }
+        Ok(jobs.into_iter().map(|job| job.payload).collect())
+    }
+
+    /// Return the payloads of jobs as deserializable structs
+    pub async fn poll<T: DeserializeOwned>(&self, delete_on_receipt: bool) -> Result<Vec<T>, GenericError> {
+        let mut resp = Vec::new();
+        for body in self.poll_strings(delete_on_receipt).await? {
+            let jz: T = match serde_json::from_str(&body) {
+                Ok(val) => val,
+                Err(_) => {
+                    println!("ERROR! Unable to deserialize the desired struct from '{}'", body);
+                    return Err(SimpleError{message:"JSON dserialization error".to_string()}.into())
+                }
+            };
+            resp.push(jz)
+        }
+        Ok(resp)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+    use crate::redis::{new_pool_from_env, rediserde};
+
+    const TEST_QUEUE: &str = "_OBSCURE_TEST_QUEUE";
+    const TEST_QUEUE_2: &str = "_OBSCURE_TEST_QUEUE_2";
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Email {
+        n: i32,
+    }
+
+    async fn clear(rpool: &RedisPool, name: &str) {
+        for suffix in ["seq", "jobs", "attempts", "priorities", "processing", "deadlines", "delayed", "dead",
+                "ready:0", "ready:5", "dedup:d", "dedup:e"] {
+            rediserde::del(rpool, &format!("{}:{}", name, suffix)).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn reliable_queue() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let rpool = new_pool_from_env().await.unwrap();
+            clear(&rpool, TEST_QUEUE).await;
+            let queue = Queue::new(&rpool, TEST_QUEUE).visibility_timeout(Duration::from_millis(100)).max_attempts(2);
+
+            // priorities, then first in first out
+            for n in 0..3 {
+                queue.enqueue(&Email{n}, 0, Duration::ZERO).await.unwrap();
+            }
+            queue.enqueue(&Email{n: 9}, 5, Duration::ZERO).await.unwrap();
+            queue.enqueue(&Email{n: 7}, 5, Duration::from_millis(200)).await.unwrap();
+            let first = queue.push(&Email{n: 3}, "g").await.unwrap();
+            assert_eq!(queue.push_str("{\"n\": 4}", "g", Some("d")).await.unwrap(), queue.push_str("{\"n\": 4}", "g", Some("d")).await.unwrap());
+            assert_ne!(first, queue.push_str("{\"n\": 4}", "g", Some("e")).await.unwrap());
+            assert_eq!(queue.count().await.unwrap(), 8);
+            let got: Vec<Email> = queue.poll(true).await.unwrap();
+            assert_eq!(got.iter().map(|e| e.n).collect::<Vec<_>>(), vec![9, 0, 1, 2, 3, 4, 4]);
+
+            // a job that isn't acked is requeued, then dead-lettered
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let jobs = queue.dequeue().await.unwrap();
+            assert_eq!(jobs.len(), 1);
+            assert_eq!((jobs[0].deserialize::<Email>().unwrap(), jobs[0].priority, jobs[0].attempts), (Email{n: 7}, 5, 1));
+            assert!(queue.dequeue().await.unwrap().is_empty());
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(queue.reap().await.unwrap(), 1);
+            let jobs = queue.dequeue().await.unwrap();
+            assert_eq!(jobs[0].attempts, 2);
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert!(queue.dequeue().await.unwrap().is_empty());
+            let dead = queue.dead_letters().await.unwrap();
+            assert_eq!(dead.len(), 1);
+            assert_eq!(queue.count().await.unwrap(), 0);
+            queue.retry_dead(&dead[0].id).await.unwrap();
+            assert!(queue.retry_dead(&dead[0].id).await.is_err());
+            let jobs = queue.dequeue().await.unwrap();
+            assert_eq!(jobs[0].attempts, 1);
+            assert!(queue.ack(&jobs[0].id).await.unwrap());
+            assert!(!queue.ack(&jobs[0].id).await.unwrap());
+
+            // a worker that crashed after LMOVE left a job without a deadline
+            let id = queue.enqueue(&Email{n: 8}, 0, Duration::ZERO).await.unwrap();
+            let mut rconn = rpool.get().await.unwrap();
+            let _ : Option<String> = redis::cmd("LMOVE").arg(format!("{}:ready:0", TEST_QUEUE)).arg(format!("{}:processing", TEST_QUEUE))
+                .arg("RIGHT").arg("LEFT").query_async(&mut *rconn).await.unwrap();
+            drop(rconn);
+            assert_eq!(queue.reap().await.unwrap(), 0);
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let jobs = queue.dequeue().await.unwrap();
+            assert_eq!(jobs[0].id, id);
+            assert!(queue.nack(&id, Duration::from_millis(50)).await.unwrap());
+            assert!(!queue.nack(&id, Duration::from_millis(50)).await.unwrap());
+            assert!(queue.dequeue().await.unwrap().is_empty());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let strings = queue.poll_strings(true).await.unwrap();
+            assert_eq!(strings, vec!["{\"n\":8}".to_string()]);
+            assert_eq!(queue.count().await.unwrap(), 0);
+            clear(&rpool, TEST_QUEUE).await;
+        })
+    }
+
+    /// Takes jobs from the queue until it is empty, abandoning the first delivery of every third job
+    /// so it has to be reaped, nacking the first delivery of the next and acking the rest
+    async fn work(queue: &Queue) -> Vec<(i32, u64)> {
+        let mut delivered = Vec::new();
+        while queue.count().await.unwrap() > 0 {
+            let jobs = queue.dequeue().await.unwrap();
+            if jobs.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            for job in jobs {
+                let n = job.deserialize::<Email>().unwrap().n;
+                delivered.push((n, job.attempts));
+                match (n % 3, job.attempts) {
+                    (0, 1) => (),
+                    (1, 1) => assert!(queue.nack(&job.id, Duration::ZERO).await.unwrap()),
+                    _ => assert!(queue.ack(&job.id).await.unwrap()),
+                }
+            }
+        }
+        delivered
+    }
+
+    #[test]
+    fn concurrent_dequeuers() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let rpool = new_pool_from_env().await.unwrap();
+            clear(&rpool, TEST_QUEUE_2).await;
+            let queue = Queue::new(&rpool, TEST_QUEUE_2).visibility_timeout(Duration::from_millis(300)).batch_size(2);
+            for n in 0..30 {
+                queue.enqueue(&Email{n}, 0, Duration::ZERO).await.unwrap();
+            }
+            let (a, b) = tokio::time::timeout(Duration::from_secs(10), async { futures::join!(work(&queue), work(&queue)) }).await.unwrap();
+
+            // every job was delivered once, or twice if it was abandoned or nacked, and never to both workers at once
+            let mut delivered: Vec<(i32, u64)> = a.into_iter().chain(b).collect();
+            delivered.sort();
+            let expected: Vec<(i32, u64)> = (0..30).flat_map(|n| if n % 3 == 2 { vec![(n, 1)] } else { vec![(n, 1), (n, 2)] }).collect();
+            assert_eq!(delivered, expected);
+            assert!(queue.dead_letters().await.unwrap().is_empty());
+            let mut rconn = rpool.get().await.unwrap();
+            for suffix in ["processing", "dead"] {
+                let len: usize = rconn.llen(format!("{}:{}", TEST_QUEUE_2, suffix)).await.unwrap();
+                assert_eq!(len, 0);
+            }
+            for suffix in ["deadlines", "delayed"] {
+                let len: usize = rconn.zcard(format!("{}:{}", TEST_QUEUE_2, suffix)).await.unwrap();
+                assert_eq!(len, 0);
+            }
+            drop(rconn);
+            clear(&rpool, TEST_QUEUE_2).await;
+        })
+    }
+}
@@ -0,0 +1,538 @@
+//! Redis Streams with consumer groups, as an alternative to SQS with at-least-once delivery, i.e.
+//! ```ignore
+//! streams::create_group(&rpool, "orders", "billing", streams::START).await?;
+//! streams::xadd(&rpool, "orders", &order, &Trim::ApproxMaxLen(100_000)).await?;
+//!
+//! let consumer = Consumer::new(&rpool, "orders", "billing", "worker-1")
+//!     .claim_after(Duration::from_secs(60))
+//!     .max_deliveries(5);
+//! let mut messages = Box::pin(consumer.stream::<Order>());
+//! while let Some(message) = messages.next().await {
+//!     let message = message?;
+//!     bill(&message.payload).await?;
+//!     consumer.ack(&[&message.id]).await?;
+//! }
+//! ```
+//! Each entry holds the payload, encoded with a rediserde codec, in a single "data" field.
+//! A message that isn't acknowledged stays pending; once it has been idle for claim_after another consumer claims it,
+//! and once it has been delivered max_deliveries times it is moved to the dead-letter stream instead
+//! ("{stream}:dead" unless set otherwise). Without claim_after a pending message is only delivered again
+//! when its consumer restarts, so it is only dead-lettered after that many restarts.
+
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::Duration};
+use futures::Stream;
+use mobc_redis::redis::{self, Value};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::core::{GenericError, SimpleError};
+use super::{RedisPool, RediserdeError, codec::{self, Codec, Json}};
+
+/// the field holding the payload
+pub const DATA_FIELD: &str = "data";
+/// create_group start position for every entry already in the stream
+pub const START: &str = "0";
+/// create_group start position for only entries added from now on
+pub const NEW: &str = "$";
+
+const DEFAULT_BATCH_SIZE: usize = 10;
+const DEFAULT_BLOCK_MS: u64 = 5000;
+const DEFAULT_MAX_DELIVERIES: u64 = 10;
+/// how long the stream waits after an error from Redis before reading again
+const ERROR_BACKOFF_MS: u64 = 1000;
+
+
+/// How xadd and trim bound the length of a stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trim {
+    /// keep every entry
+    None,
+    /// keep at most this many entries
+    MaxLen(usize),
+    /// keep at least this many entries, trimming only whole macro nodes, which is much cheaper
+    ApproxMaxLen(usize),
+    /// drop entries with ids lower than this one, i.e. older than a millisecond timestamp
+    MinId(String),
+}
+
+impl Trim {
+    fn args(&self, cmd: &mut redis::Cmd) {
+        match self {
+            Trim::None => (),
+            Trim::MaxLen(n) => { cmd.arg("MAXLEN").arg("=").arg(*n); },
+            Trim::ApproxMaxLen(n) => { cmd.arg("MAXLEN").arg("~").arg(*n); },
+            Trim::MinId(id) => { cmd.arg("MINID").arg("=").arg(id); },
+        }
+    }
+}
+
+/// A message read by a consumer. Ack it once handled, or it will be delivered again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message<T> {
+    pub id: String,
+    pub payload: T,
+    /// how many times the message has been delivered, including this time
+    pub deliveries: u64,
+}
+
+/// A message moved to a dead-letter stream
+#[derive(Debug)]
+pub struct DeadLetter<T> {
+    /// the id in the dead-letter stream
+    pub id: String,
+    /// the id in the stream it came from
+    pub original_id: String,
+    pub group: String,
+    pub deliveries: u64,
+    /// an Err if the payload can't be decoded, which is often why it was dead-lettered
+    pub payload: Result<T, GenericError>,
+}
+
+
+/// Append a struct to a stream, returning its id
+pub async fn xadd<T: Serialize>(pool: &RedisPool, stream: &str, value: &T, trim: &Trim) -> Result<String, GenericError> {
+    xadd_with(pool, stream, value, trim, Json).await
+}
+
+/// xadd, encoding the struct with a codec
+pub async fn xadd_with<T: Serialize, C: Codec>(pool: &RedisPool, stream: &str, value: &T, trim: &Trim, codec: C) -> Result<String, GenericError> {
+    let mut rconn = pool.get().await?;
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(stream);
+    trim.args(&mut cmd);
+    cmd.arg("*").arg(DATA_FIELD).arg(codec.encode(value)?);
+    let id: String = cmd.query_async(&mut *rconn).await.map_err(|e| RediserdeError::from_redis(stream, e))?;
+    Ok(id)
+}
+
+/// Trim a stream, returning how many entries were removed
+pub async fn trim(pool: &RedisPool, stream: &str, trim: &Trim) -> Result<usize, GenericError> {
+    if *trim == Trim::None {
+        return Ok(0)
+    }
+    let mut rconn = pool.get().await?;
+    let mut cmd = redis::cmd("XTRIM");
+    cmd.arg(stream);
+    trim.args(&mut cmd);
+    let n: usize = cmd.query_async(&mut *rconn).await.map_err(|e| RediserdeError::from_redis(stream, e))?;
+    Ok(n)
+}
+
+/// The number of entries in a stream
+pub async fn len(pool: &RedisPool, stream: &str) -> Result<usize, GenericError> {
+    let mut rconn = pool.get().await?;
+    let n: usize = redis::cmd("XLEN").arg(stream).query_async(&mut *rconn).await.map_err(|e| RediserdeError::from_redis(stream, e))?;
+    Ok(n)
+}
+
+/// Create a consumer group reading from start (START, NEW or an entry id), creating the stream if needed.
+/// Returns false if the group already exists
+pub async fn create_group(pool: &RedisPool, stream: &str, group: &str, start: &str) -> Result<bool, GenericError> {
+    let mut rconn = pool.get().await?;
+    let created: Result<(), _> = redis::cmd("XGROUP").arg("CREATE").arg(stream).arg(group).arg(start).arg("MKSTREAM")
+        .query_async(&mut *rconn).await;
+    match created {
+        Ok(()) => Ok(true),
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(false),
+        Err(e) => Err(RediserdeError::from_redis(stream, e).into()),
+    }
+}
+
+/// Delete a consumer group and everything pending in it
+pub async fn destroy_group(pool: &RedisPool, stream: &str, group: &str) -> Result<bool, GenericError> {
+    let mut rconn = pool.get().await?;
+    let destroyed: usize = redis::cmd("XGROUP").arg("DESTROY").arg(stream).arg(group).query_async(&mut *rconn).await?;
+    Ok(destroyed == 1)
+}
+
+
+fn unexpected(what: &str, value: &Value) -> GenericError {
+    SimpleError{message: format!("unexpected {} in stream reply: {:?}", what, value)}.into()
+}
+
+fn string(value: &Value) -> Result<String, GenericError> {
+    match value {
+        Value::Data(bytes) => Ok(String::from_utf8(bytes.clone())?),
+        Value::Status(s) => Ok(s.clone()),
+        other => Err(unexpected("string", other)),
+    }
+}
+
+fn bulk(value: &Value) -> Result<&[Value], GenericError> {
+    match value {
+        Value::Bulk(values) => Ok(values),
+        Value::Nil => Ok(&[]),
+        other => Err(unexpected("array", other)),
+    }
+}
+
+/// An entry's id and fields. Entries deleted while pending have no fields
+type Entry = (String, Option<HashMap<String, Vec<u8>>>);
+
+/// A batch of messages, with Err for those whose payload can't be decoded
+type Batch<T> = Vec<Result<Message<T>, GenericError>>;
+
+/// Parse an entry from [id, [field, value, ...]]
+fn entry(value: &Value) -> Result<Entry, GenericError> {
+    let parts = bulk(value)?;
+    if parts.len() != 2 {
+        return Err(unexpected("entry", value))
+    }
+    let id = string(&parts[0])?;
+    if parts[1] == Value::Nil {
+        return Ok((id, None))
+    }
+    let mut fields = HashMap::new();
+    for pair in bulk(&parts[1])?.chunks(2) {
+        if let [field, Value::Data(data)] = pair {
+            fields.insert(string(field)?, data.clone());
+        }
+    }
+    Ok((id, Some(fields)))
+}
+
+
+/// Reads a stream as one consumer in a group
+pub struct Consumer {
+    pool: RedisPool,
+    stream: String,
+    group: String,
+    name: String,
+    batch_size: usize,
+    block: Duration,
+    claim_after: Option<Duration>,
+    max_deliveries: u64,
+    dead_letter: String,
+    /// where reading this consumer's own pending messages has got to, until they have all been read again
+    recovering: Mutex<Option<String>>,
+}
+
+impl Consumer {
+
+    /// Instantiate a consumer. name should be unique within the group and stable across restarts,
+    /// so messages pending when it stopped are delivered to it again
+    pub fn new(pool: &RedisPool, stream: &str, group: &str, name: &str) -> Self {
+        Consumer {
+            pool: pool.clone(),
+            stream: stream.to_string(),
+            group: group.to_string(),
+            name: name.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            block: Duration::from_millis(DEFAULT_BLOCK_MS),
+            claim_after: None,
+            max_deliveries: DEFAULT_MAX_DELIVERIES,
+            dead_letter: format!("{}:dead", stream),
+            recovering: Mutex::new(Some(START.to_string())),
+        }
+    }
+
+    /// The most messages read at once
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long a read waits for new messages. The pooled connection is held while waiting
+    pub fn block(mut self, block: Duration) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Claim messages that have been pending with any consumer for this long, i.e. because it died or didn't ack them.
+    /// Without this, messages are only redelivered to the consumer they were pending with, when it restarts
+    pub fn claim_after(mut self, claim_after: Duration) -> Self {
+        self.claim_after = Some(claim_after);
+        self
+    }
+
+    /// How many times a message is delivered before it is moved to the dead-letter stream. Deliveries are counted when
+    /// a message is claimed (see claim_after) or re-read by its consumer after a restart, so without claim_after
+    /// a message that is never acked stays pending until the consumer has restarted this many times
+    pub fn max_deliveries(mut self, max_deliveries: u64) -> Self {
+        self.max_deliveries = max_deliveries;
+        self
+    }
+
+    pub fn dead_letter_stream(mut self, dead_letter: &str) -> Self {
+        self.dead_letter = dead_letter.to_string();
+        self
+    }
+
+    fn decode<T: DeserializeOwned>(&self, id: String, fields: HashMap<String, Vec<u8>>, deliveries: u64) -> Result<Message<T>, GenericError> {
+        let data = fields.get(DATA_FIELD).ok_or_else(|| SimpleError{message: format!("entry {} in {} has no {} field", id, self.stream, DATA_FIELD)})?;
+        match codec::decode(data) {
+            Ok(payload) => Ok(Message{id, payload, deliveries}),
+            Err(source) => Err(RediserdeError::Decode{key: format!("{} {}", self.stream, id), source}.into()),
+        }
+    }
+
+    /// Read the next batch: to begin with, messages left pending with this consumer when it last stopped,
+    /// then messages stuck with other consumers, then new messages, waiting up to block for them.
+    /// A payload that can't be decoded is an Err in the batch and is left pending, to be redelivered and in time
+    /// dead-lettered if claim_after is set, otherwise only when this consumer restarts (see max_deliveries)
+    pub async fn read<T: DeserializeOwned>(&self) -> Result<Batch<T>, GenericError> {
+        let recovering = self.recovering.lock().unwrap().clone();
+        if let Some(after) = recovering {
+            let own = self.read_group(&after, None).await?;
+            *self.recovering.lock().unwrap() = own.last().map(|(id, _)| id.clone());
+            if !own.is_empty() {
+                let start = if after == START { "-".to_string() } else { format!("({}", after) };
+                let counts = self.delivery_counts(Duration::ZERO, &start, Some(&self.name)).await?;
+                return self.dead_letter_and_decode(own, &counts).await
+            }
+        }
+        if let Some(idle) = self.claim_after {
+            let claimed = self.claim(idle).await?;
+            if !claimed.is_empty() {
+                return Ok(claimed)
+            }
+        }
+        let new = self.read_group(">", Some(self.block)).await?;
+        Ok(new.into_iter().filter_map(|(id, fields)| fields.map(|f| self.decode(id, f, 1))).collect())
+    }
+
+    /// XREADGROUP from id, which is ">" for new messages or an id for this consumer's pending ones
+    async fn read_group(&self, id: &str, block: Option<Duration>) -> Result<Vec<Entry>, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let mut cmd = redis::cmd("XREADGROUP");
+        cmd.arg("GROUP").arg(&self.group).arg(&self.name).arg("COUNT").arg(self.batch_size);
+        if let Some(block) = block {
+            cmd.arg("BLOCK").arg(block.as_millis() as u64);
+        }
+        cmd.arg("STREAMS").arg(&self.stream).arg(id);
+        let reply: Value = cmd.query_async(&mut *rconn).await.map_err(|e| RediserdeError::from_redis(&self.stream, e))?;
+        let mut entries = Vec::new();
+        for stream in bulk(&reply)? {
+            if let [_, stream_entries] = bulk(stream)? {
+                for e in bulk(stream_entries)? {
+                    entries.push(entry(e)?);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Delivery counts of pending messages idle for at least idle, from start onwards, by id
+    async fn delivery_counts(&self, idle: Duration, start: &str, consumer: Option<&str>) -> Result<HashMap<String, u64>, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let mut cmd = redis::cmd("XPENDING");
+        cmd.arg(&self.stream).arg(&self.group).arg("IDLE").arg(idle.as_millis() as u64).arg(start).arg("+").arg(self.batch_size);
+        if let Some(consumer) = consumer {
+            cmd.arg(consumer);
+        }
+        let reply: Value = cmd.query_async(&mut *rconn).await.map_err(|e| RediserdeError::from_redis(&self.stream, e))?;
+        // each is [id, consumer, idle ms, deliveries]
+        let mut counts = HashMap::new();
+        for pending in bulk(&reply)? {
+            match bulk(pending)? {
+                [id, _, _, Value::Int(deliveries)] => counts.insert(string(id)?, *deliveries as u64),
+                _ => return Err(unexpected("XPENDING entry", pending)),
+            };
+        }
+        Ok(counts)
+    }
+
+    /// Claim messages idle for at least idle with XAUTOCLAIM, dead-lettering those delivered too often
+    async fn claim<T: DeserializeOwned>(&self, idle: Duration) -> Result<Batch<T>, GenericError> {
+        let counts = self.delivery_counts(idle, "-", None).await?;
+        if counts.is_empty() {
+            return Ok(Vec::new())
+        }
+        let mut rconn = self.pool.get().await?;
+        let reply: Value = redis::cmd("XAUTOCLAIM").arg(&self.stream).arg(&self.group).arg(&self.name)
+            .arg(idle.as_millis() as u64).arg("0-0").arg("COUNT").arg(self.batch_size)
+            .query_async(&mut *rconn).await.map_err(|e| RediserdeError::from_redis(&self.stream, e))?;
+        drop(rconn);
+        let parts = bulk(&reply)?;
+        let claimed = match parts.get(1) {
+            Some(claimed) => bulk(claimed)?.iter().map(entry).collect::<Result<Vec<_>, _>>()?,
+            None => return Err(unexpected("XAUTOCLAIM reply", &reply)),
+        };
+        // claiming was a delivery too
+        let counts = counts.into_iter().map(|(id, n)| (id, n + 1)).collect();
+        self.dead_letter_and_decode(claimed, &counts).await
+    }
+
+    /// Move messages delivered more than max_deliveries times to the dead-letter stream and decode the rest
+    async fn dead_letter_and_decode<T: DeserializeOwned>(&self, entries: Vec<Entry>, counts: &HashMap<String, u64>) -> Result<Batch<T>, GenericError> {
+        let mut messages = Vec::new();
+        for (id, fields) in entries {
+            let deliveries = counts.get(&id).copied().unwrap_or(1);
+            match fields {
+                // trimmed away while pending, so there is nothing left to deliver
+                None => { self.ack(&[&id]).await?; },
+                Some(fields) if deliveries > self.max_deliveries => self.dead_letter(&id, fields, deliveries).await?,
+                Some(fields) => messages.push(self.decode(id, fields, deliveries)),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Copy a message to the dead-letter stream and acknowledge it, atomically
+    async fn dead_letter(&self, id: &str, fields: HashMap<String, Vec<u8>>, deliveries: u64) -> Result<(), GenericError> {
+        println!("ERROR! {} moved message {} to {} after {} deliveries", self.group, id, self.dead_letter, deliveries);
+        let data = fields.get(DATA_FIELD).cloned().unwrap_or_default();
+        let mut rconn = self.pool.get().await?;
+        let _ : () = redis::pipe().atomic()
+            .cmd("XADD").arg(&self.dead_letter).arg("*").arg(DATA_FIELD).arg(data)
+                .arg("original_id").arg(id).arg("group").arg(&self.group).arg("deliveries").arg(deliveries).ignore()
+            .cmd("XACK").arg(&self.stream).arg(&self.group).arg(id).ignore()
+            .query_async(&mut *rconn).await?;
+        Ok(())
+    }
+
+    /// Acknowledge handled messages, returning how many were pending
+    pub async fn ack(&self, ids: &[&str]) -> Result<usize, GenericError> {
+        if ids.is_empty() {
+            return Ok(0)
+        }
+        let mut rconn = self.pool.get().await?;
+        let n: usize = redis::cmd("XACK").arg(&self.stream).arg(&self.group).arg(ids).query_async(&mut *rconn).await?;
+        Ok(n)
+    }
+
+    /// The number of messages delivered to the group but not yet acknowledged
+    pub async fn pending(&self) -> Result<usize, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let summary: Value = redis::cmd("XPENDING").arg(&self.stream).arg(&self.group).query_async(&mut *rconn).await?;
+        match bulk(&summary)?.first() {
+            Some(Value::Int(n)) => Ok(*n as usize),
+            _ => Err(unexpected("XPENDING summary", &summary)),
+        }
+    }
+
+    /// The oldest messages in the dead-letter stream. A payload that can't be decoded is an Err in its letter,
+    /// rather than failing the whole listing
+    pub async fn dead_letters<T: DeserializeOwned>(&self, count: usize) -> Result<Vec<DeadLetter<T>>, GenericError> {
+        let mut rconn = self.pool.get().await?;
+        let reply: Value = redis::cmd("XRANGE").arg(&self.dead_letter).arg("-").arg("+").arg("COUNT").arg(count)
+            .query_async(&mut *rconn).await?;
+        let mut letters = Vec::new();
+        for e in bulk(&reply)? {
+            let (id, fields) = entry(e)?;
+            let fields = fields.unwrap_or_default();
+            let field = |name: &str| String::from_utf8(fields.get(name).cloned().unwrap_or_default());
+            letters.push(DeadLetter {
+                original_id: field("original_id")?,
+                group: field("group")?,
+                deliveries: field("deliveries")?.parse().map_err(|_| SimpleError{message: format!("dead letter {} has no valid delivery count", id)})?,
+                payload: codec::decode(fields.get(DATA_FIELD).map(Vec::as_slice).unwrap_or_default())
+                    .map_err(|source| RediserdeError::Decode{key: format!("{} {}", self.dead_letter, id), source}.into()),
+                id,
+            });
+        }
+        Ok(letters)
+    }
+
+    /// Messages as they arrive, with Err items for payloads that can't be decoded and errors from Redis,
+    /// after which reading carries on. Ack each message once it has been handled
+    pub fn stream<T: DeserializeOwned + 'static>(&self) -> impl Stream<Item = Result<Message<T>, GenericError>> + '_ {
+        futures::stream::unfold(VecDeque::new(), move |mut buffered| async move {
+            loop {
+                if let Some(item) = buffered.pop_front() {
+                    return Some((item, buffered))
+                }
+                match self.read().await {
+                    Ok(batch) => buffered.extend(batch),
+                    Err(e) => {
+                        tokio::time::sleep(Duration::from_millis(ERROR_BACKOFF_MS)).await;
+                        return Some((Err(e), buffered))
+                    },
+                }
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use tokio::runtime::Runtime;
+    use crate::redis::{new_pool_from_env, rediserde};
+
+    const TEST_STREAM: &str = "_OBSCURE_TEST_STREAM";
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Job {
+        n: i32,
+    }
+
+    #[test]
+    fn consumer_groups() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let rpool = new_pool_from_env().await.unwrap();
+            let dead = format!("{}:dead", TEST_STREAM);
+            rediserde::del(&rpool, TEST_STREAM).await.unwrap();
+            rediserde::del(&rpool, &dead).await.unwrap();
+            assert!(create_group(&rpool, TEST_STREAM, "g", START).await.unwrap());
+            assert!(!create_group(&rpool, TEST_STREAM, "g", START).await.unwrap());
+            for n in 0..3 {
+                xadd(&rpool, TEST_STREAM, &Job{n}, &Trim::None).await.unwrap();
+            }
+            // a payload that isn't a Job
+            xadd(&rpool, TEST_STREAM, &"garbage", &Trim::None).await.unwrap();
+
+            let a = Consumer::new(&rpool, TEST_STREAM, "g", "a").batch_size(2).block(Duration::from_millis(50));
+            let b = Consumer::new(&rpool, TEST_STREAM, "g", "b").block(Duration::from_millis(50))
+                .claim_after(Duration::from_millis(100)).max_deliveries(2);
+            let batch = a.read::<Job>().await.unwrap();
+            let got: Vec<Message<Job>> = batch.into_iter().map(Result::unwrap).collect();
+            assert_eq!(got.iter().map(|m| (m.payload.n, m.deliveries)).collect::<Vec<_>>(), vec![(0, 1), (1, 1)]);
+            assert_eq!(a.ack(&[&got[0].id]).await.unwrap(), 1);
+            // the stream yields the rest, with an error for the garbage
+            let mut messages = Box::pin(b.stream::<Job>());
+            let m = messages.next().await.unwrap().unwrap();
+            assert_eq!(m.payload.n, 2);
+            b.ack(&[&m.id]).await.unwrap();
+            assert!(matches!(messages.next().await.unwrap().unwrap_err().downcast_ref::<RediserdeError>(), Some(RediserdeError::Decode{..})));
+            drop(messages);
+            assert_eq!(b.pending().await.unwrap(), 2);
+
+            // once idle, b claims what a never acked, and a restarted a gets nothing
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let claimed = b.read::<Job>().await.unwrap();
+            assert_eq!(claimed.len(), 2);
+            let job = claimed.into_iter().find_map(Result::ok).unwrap();
+            assert_eq!((job.payload.n, job.deliveries), (1, 2));
+            assert!(a.read::<Job>().await.unwrap().is_empty());
+            // after a third delivery both are dead-lettered instead
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert!(b.read::<Job>().await.unwrap().is_empty());
+            assert_eq!(b.pending().await.unwrap(), 0);
+            let letters: Vec<DeadLetter<Job>> = b.dead_letters(10).await.unwrap();
+            assert_eq!(letters.len(), 2);
+            assert!(letters.iter().all(|l| l.group == "g" && l.deliveries == 3));
+            // the garbage is listed too, with its own error
+            assert!(letters.iter().any(|l| matches!(l.payload, Ok(Job{n: 1}))));
+            let garbage = letters.iter().find_map(|l| l.payload.as_ref().err()).unwrap();
+            assert!(matches!(garbage.downcast_ref::<RediserdeError>(), Some(RediserdeError::Decode{..})));
+            // but a letter without a delivery count is an error rather than 0 deliveries
+            let mut rconn = rpool.get().await.unwrap();
+            let _ : String = redis::cmd("XADD").arg(&dead).arg("*").arg("original_id").arg("0-1").arg("group").arg("g")
+                .arg("deliveries").arg("lots").arg(DATA_FIELD).arg("{}").query_async(&mut *rconn).await.unwrap();
+            drop(rconn);
+            assert!(b.dead_letters::<Job>(10).await.is_err());
+
+            // a consumer restarted under the same name gets back what it had pending, once
+            xadd(&rpool, TEST_STREAM, &Job{n: 5}, &Trim::None).await.unwrap();
+            let c = Consumer::new(&rpool, TEST_STREAM, "g", "c").block(Duration::from_millis(50));
+            assert_eq!(c.read::<Job>().await.unwrap().remove(0).unwrap().payload.n, 5);
+            let c = Consumer::new(&rpool, TEST_STREAM, "g", "c").block(Duration::from_millis(50));
+            let m = c.read::<Job>().await.unwrap().remove(0).unwrap();
+            assert_eq!(m.payload.n, 5);
+            assert!(c.read::<Job>().await.unwrap().is_empty());
+            c.ack(&[&m.id]).await.unwrap();
+
+            // trimming
+            assert_eq!(len(&rpool, TEST_STREAM).await.unwrap(), 5);
+            xadd(&rpool, TEST_STREAM, &Job{n: 4}, &Trim::MaxLen(3)).await.unwrap();
+            assert_eq!(len(&rpool, TEST_STREAM).await.unwrap(), 3);
+            assert_eq!(trim(&rpool, TEST_STREAM, &Trim::MaxLen(1)).await.unwrap(), 2);
+            assert!(destroy_group(&rpool, TEST_STREAM, "g").await.unwrap());
+            rediserde::del(&rpool, TEST_STREAM).await.unwrap();
+            rediserde::del(&rpool, &dead).await.unwrap();
+        })
+    }
+}
@@ -84,11 +84,18 @@ impl Messenger {
     /// publish a message (could be a string or serializable struct) to the queue with a given group_id
     pub async fn push<T: Serialize>(&self, msg: &T, group_id: &str) -> Result<String, GenericError> {
         let body = serde_json::to_string(msg)?;
+        self.push_str(&body, group_id, None).await
+    }
+
+    /// publish a message body as is. On a FIFO queue, messages sent with the same dedup_id
+    /// within five minutes of each other are only delivered once
+    pub async fn push_str(&self, body: &str, group_id: &str, dedup_id: Option<&str>) -> Result<String, GenericError> {
         let smo = self.client
             .send_message()
             .queue_url(&self.queue_url)
             .message_body(body)
             .message_group_id(group_id)
+            .set_message_deduplication_id(dedup_id.map(|id| id.to_string()))
             .send().await?;
         let message_id = smo
             .message_id